@@ -0,0 +1,180 @@
+use std::io::{self, Write};
+use crate::{DataError, Db};
+
+// A backup is a sequence of records, each a tag byte and then its fields, every field
+// prefixed with its length (u32 LE):
+//   ENTRY: key, value exactly as stored (so still compressed/encrypted as configured)
+//   CHECKPOINT: the last key written so far; everything up to it is complete
+//   END: no fields; the backup finished
+const ENTRY: u8 = 0;
+const CHECKPOINT: u8 = 1;
+const END: u8 = 2;
+
+const CHECKPOINT_EVERY: u64 = 1024;
+
+/// How far a backup has got. Reported after every checkpoint and once more when it finishes.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BackupProgress {
+    pub keys: u64,
+    /// Key and value bytes written, not counting the stream's framing.
+    pub bytes: u64,
+    /// The last key covered by a checkpoint record, to resume an interrupted backup from.
+    pub checkpoint: Option<Vec<u8>>
+}
+
+pub(crate) fn write_backup(db: &dyn Db, mut writer: impl Write, resume_after: Option<&[u8]>,
+                           mut progress: impl FnMut(BackupProgress)) -> Result<BackupProgress, DataError> {
+    // the smallest key greater than the checkpoint
+    let start = resume_after.map(|key| {
+        let mut start = key.to_vec();
+        start.push(0);
+        start
+    });
+
+    let mut done = BackupProgress::default();
+    let mut last_key = vec![];
+    let mut write_result = Ok(());
+    db.scan_stored(start.as_deref(), None, &mut |key, value| {
+        if let Err(err) = write_record(&mut writer, ENTRY, &[key, value]) {
+            write_result = Err(err);
+            return false;
+        }
+
+        done.keys += 1;
+        done.bytes += (key.len() + value.len()) as u64;
+        last_key.clear();
+        last_key.extend_from_slice(key);
+        if done.keys % CHECKPOINT_EVERY != 0 { return true; }
+
+        match write_record(&mut writer, CHECKPOINT, &[key]) {
+            Err(err) => {
+                write_result = Err(err);
+                false
+            },
+            Ok(_) => {
+                done.checkpoint = Some(key.to_vec());
+                progress(done.clone());
+                true
+            }
+        }
+    })?;
+    write_result?;
+
+    if done.keys % CHECKPOINT_EVERY != 0 {
+        write_record(&mut writer, CHECKPOINT, &[&last_key])?;
+        done.checkpoint = Some(last_key);
+    }
+    write_record(&mut writer, END, &[])?;
+    writer.flush()?;
+
+    progress(done.clone());
+    Ok(done)
+}
+
+fn write_record(writer: &mut impl Write, tag: u8, fields: &[&[u8]]) -> io::Result<()> {
+    writer.write_all(&[tag])?;
+    for field in fields {
+        let Ok(len) = u32::try_from(field.len())
+            else { return Err(io::Error::new(io::ErrorKind::InvalidInput, "backup field over 4 GiB")) };
+        writer.write_all(&len.to_le_bytes())?;
+        writer.write_all(field)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryDb;
+
+    // Splits a backup stream back into (tag, fields) records
+    fn records(mut stream: &[u8]) -> Vec<(u8, Vec<Vec<u8>>)> {
+        fn field(stream: &mut &[u8]) -> Vec<u8> {
+            let len = u32::from_le_bytes(stream[..4].try_into().unwrap()) as usize;
+            let value = stream[4..4 + len].to_vec();
+            *stream = &stream[4 + len..];
+            value
+        }
+        let mut records = vec![];
+        while let Some((&tag, rest)) = stream.split_first() {
+            stream = rest;
+            let fields = match tag {
+                ENTRY => vec![field(&mut stream), field(&mut stream)],
+                CHECKPOINT => vec![field(&mut stream)],
+                END => vec![],
+                _ => panic!("unknown record tag {tag}")
+            };
+            records.push((tag, fields));
+        }
+        records
+    }
+
+    fn db_with(keys: u64) -> MemoryDb {
+        let db = MemoryDb::default();
+        for i in 0..keys {
+            db.save_data(&i.to_be_bytes().to_vec(), &[i as u8]).unwrap();
+        }
+        db
+    }
+
+    #[test]
+    fn frames_entries_then_checkpoint_then_end() {
+        let db = MemoryDb::default();
+        db.save_data(&vec![], b"").unwrap();
+        db.save_data(&b"key".to_vec(), b"value").unwrap();
+        let mut stream = vec![];
+        let mut reports = vec![];
+        let done = write_backup(&db, &mut stream, None, |progress| reports.push(progress)).unwrap();
+
+        assert_eq!(records(&stream), vec![
+            (ENTRY, vec![vec![], vec![]]),
+            (ENTRY, vec![b"key".to_vec(), b"value".to_vec()]),
+            (CHECKPOINT, vec![b"key".to_vec()]),
+            (END, vec![])
+        ]);
+        assert_eq!(done, BackupProgress { keys: 2, bytes: 8, checkpoint: Some(b"key".to_vec()) });
+        assert_eq!(reports, vec![done]);
+    }
+
+    #[test]
+    fn checkpoints_every_so_many_keys() {
+        let db = db_with(CHECKPOINT_EVERY * 2);
+        let mut stream = vec![];
+        let mut reports = vec![];
+        write_backup(&db, &mut stream, None, |progress| reports.push(progress)).unwrap();
+
+        let checkpoints: Vec<_> = records(&stream).into_iter()
+            .filter(|(tag, _)| *tag == CHECKPOINT)
+            .map(|(_, fields)| fields[0].clone())
+            .collect();
+        let every = (CHECKPOINT_EVERY - 1).to_be_bytes().to_vec();
+        let last = (CHECKPOINT_EVERY * 2 - 1).to_be_bytes().to_vec();
+        // a checkpoint that lands on the last key isn't written twice
+        assert_eq!(checkpoints, vec![every.clone(), last.clone()]);
+        // but the finished backup is still reported once more
+        let reported: Vec<_> = reports.iter().map(|progress| progress.checkpoint.clone().unwrap()).collect();
+        assert_eq!(reported, vec![every, last.clone(), last]);
+    }
+
+    #[test]
+    fn resumes_after_checkpoint() {
+        let db = db_with(4);
+        let mut stream = vec![];
+        let done = write_backup(&db, &mut stream, Some(&1u64.to_be_bytes()), |_| ()).unwrap();
+
+        let keys: Vec<_> = records(&stream).into_iter()
+            .filter(|(tag, _)| *tag == ENTRY)
+            .map(|(_, fields)| fields[0].clone())
+            .collect();
+        assert_eq!(keys, vec![2u64.to_be_bytes().to_vec(), 3u64.to_be_bytes().to_vec()]);
+        assert_eq!(done.keys, 2);
+    }
+
+    #[test]
+    fn empty_partition_still_ends() {
+        let mut stream = vec![];
+        let done = write_backup(&MemoryDb::default(), &mut stream, None, |_| ()).unwrap();
+        assert_eq!(records(&stream), vec![(END, vec![])]);
+        assert_eq!(done, BackupProgress::default());
+    }
+}
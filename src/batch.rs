@@ -0,0 +1,171 @@
+use std::collections::BTreeMap;
+use std::mem;
+use crate::{locks, BatchOp, DataError, SafeDataProvider};
+
+/// Buffers puts and deletes against one partition and applies them in a single atomic
+/// write, so readers never see part of the batch without the rest.
+pub struct DataBatch {
+    partition_id: String,
+    ops: Vec<BatchOp>,
+    bytes: usize,
+    flush_at: Option<usize>,
+    flushed_ops: usize,
+    flush_points: Vec<usize>,
+    flush_error: Option<DataError>
+}
+
+impl DataBatch {
+    pub fn new(partition_id: &str) -> Self {
+        DataBatch {
+            partition_id: partition_id.to_string(),
+            ops: vec![],
+            bytes: 0,
+            flush_at: None,
+            flushed_ops: 0,
+            flush_points: vec![],
+            flush_error: None
+        }
+    }
+
+    /// Has the batch commit what it has buffered, and start over empty, whenever the buffered
+    /// keys and values add up to `bytes` or more, keeping memory bounded on very large
+    /// operations. This gives up the batch's atomicity: each flush is atomic on its own, but
+    /// readers can see earlier flushes before later ones land, and a failure leaves earlier
+    /// flushes written. After a failed flush the batch stops writing (and buffering), and
+    /// commit returns that flush's error.
+    pub fn auto_flush_at(&mut self, bytes: usize) -> &mut Self {
+        self.flush_at = Some(bytes);
+        self
+    }
+
+    pub fn put(&mut self, key: Vec<u8>, data: Vec<u8>) -> &mut Self {
+        let size = key.len() + data.len();
+        self.buffer(BatchOp::Put(key, data), size);
+        self
+    }
+
+    pub fn delete(&mut self, key: Vec<u8>) -> &mut Self {
+        let size = key.len();
+        self.buffer(BatchOp::Delete(key), size);
+        self
+    }
+
+    /// Number of ops buffered, i.e. not yet written by an auto-flush.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Bytes of keys and values buffered.
+    pub fn size_bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// For each auto-flush so far, the number of ops (counted from the start of the batch)
+    /// written once it was done.
+    pub fn flush_points(&self) -> &[usize] {
+        &self.flush_points
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Reports what committing would do to each key the buffered ops touch, by its final op,
+    /// without writing anything. Reads go straight to the store; a concurrent write can of
+    /// course still change the outcome before an eventual commit.
+    pub fn dry_run(&self) -> Result<BatchPreview, DataError> {
+        SafeDataProvider::observe("dry_run_batch", &self.partition_id, || {
+            let mut outcomes = BTreeMap::new();
+            for op in &self.ops {
+                match op {
+                    BatchOp::Put(key, data) => { outcomes.insert(key, Some(data)); },
+                    BatchOp::Delete(key) => { outcomes.insert(key, None); }
+                }
+            }
+
+            let db = SafeDataProvider::get_db(&self.partition_id)?;
+            let mut preview = BatchPreview::default();
+            for (key, outcome) in outcomes {
+                let current = match db.get_data(key) {
+                    Err(DataError::DataNotFound) => None,
+                    Err(err) => return Err(err),
+                    Ok(data) => Some(data)
+                };
+
+                let keys = match (current, outcome) {
+                    (None, None) => &mut preview.unchanged,
+                    (None, Some(_)) => &mut preview.new,
+                    (Some(_), None) => &mut preview.deleted,
+                    (Some(current), Some(data)) if current == *data => &mut preview.unchanged,
+                    (Some(_), Some(_)) => &mut preview.changed
+                };
+                keys.push(key.clone());
+            }
+            Ok(preview)
+        })
+    }
+
+    pub fn commit(mut self) -> Result<(), DataError> {
+        // a failed auto-flush was already reported when it happened
+        if let Some(err) = self.flush_error.take() { return Err(err); }
+        let partition_id = self.partition_id.clone();
+        SafeDataProvider::observe("commit_batch", &partition_id, || self.apply())
+    }
+
+    pub(crate) fn apply(self) -> Result<(), DataError> {
+        if let Some(err) = self.flush_error { return Err(err); }
+        Self::write(&self.partition_id, self.ops)
+    }
+
+    fn buffer(&mut self, op: BatchOp, size: usize) {
+        if self.flush_error.is_some() { return; }
+        self.ops.push(op);
+        self.bytes += size;
+
+        let Some(limit) = self.flush_at
+            else { return };
+        if self.bytes < limit { return; }
+
+        let ops = mem::take(&mut self.ops);
+        let count = ops.len();
+        self.bytes = 0;
+        match SafeDataProvider::observe("commit_batch", &self.partition_id, || Self::write(&self.partition_id, ops)) {
+            Err(err) => self.flush_error = Some(err),
+            Ok(_) => {
+                self.flushed_ops += count;
+                self.flush_points.push(self.flushed_ops);
+            }
+        }
+    }
+
+    fn write(partition_id: &str, ops: Vec<BatchOp>) -> Result<(), DataError> {
+        let db = SafeDataProvider::get_db(partition_id)?;
+        let keys = ops.iter().map(|op| match op {
+            BatchOp::Put(key, _) | BatchOp::Delete(key) => key
+        });
+        let _guards = locks::lock_keys(keys, partition_id);
+        db.write_batch(&ops)?;
+
+        // ops are replayed in order so a key touched twice ends up cached as its last write
+        for op in ops {
+            match op {
+                BatchOp::Delete(key) => SafeDataProvider::invalidate_cache_entry(&key, partition_id),
+                BatchOp::Put(key, data) => {
+                    SafeDataProvider::invalidate_cache_entry(&key, partition_id);
+                    SafeDataProvider::cache_written_data(&key, partition_id, data);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What a batch would do, by key, in key order. Deleting a key that doesn't exist counts as
+/// unchanged.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BatchPreview {
+    pub new: Vec<Vec<u8>>,
+    pub changed: Vec<Vec<u8>>,
+    pub unchanged: Vec<Vec<u8>>,
+    pub deleted: Vec<Vec<u8>>
+}
@@ -0,0 +1,106 @@
+use pneumatic_core::encoding::serialize_to_bytes_rmp;
+use rmpv::Value;
+use serde::Serialize;
+use crate::DataError;
+
+/// Serializes `value` to MessagePack the way serialize_to_bytes_rmp does, except that every
+/// map's entries are ordered by their encoded keys, so the output doesn't depend on e.g.
+/// HashMap iteration order. Equal values of the same type then always give byte-identical
+/// output, on any node, as long as their Serialize impls are deterministic themselves; the
+/// result still deserializes with deserialize_rmp_to.
+pub fn serialize_canonical<T: Serialize>(value: &T) -> Result<Vec<u8>, DataError> {
+    let serialized = match serialize_to_bytes_rmp(value) {
+        Err(err) => return Err(DataError::SerializationError(err.to_string())),
+        Ok(serialized) => serialized
+    };
+
+    let Ok(value) = rmpv::decode::read_value(&mut serialized.as_slice())
+        else { return Err(DataError::SerializationError("serializer produced invalid MessagePack".to_string())) };
+    Ok(encode(&canonicalize(value)))
+}
+
+fn canonicalize(value: Value) -> Value {
+    match value {
+        Value::Array(items) => Value::Array(items.into_iter().map(canonicalize).collect()),
+        Value::Map(entries) => {
+            let mut entries: Vec<(Vec<u8>, Value, Value)> = entries.into_iter()
+                .map(|(key, value)| {
+                    let key = canonicalize(key);
+                    (encode(&key), key, canonicalize(value))
+                })
+                .collect();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            Value::Map(entries.into_iter().map(|(_, key, value)| (key, value)).collect())
+        },
+        other => other
+    }
+}
+
+// rmpv writes every integer in its smallest form, so re-encoding normalizes those too
+fn encode(value: &Value) -> Vec<u8> {
+    let mut encoded = vec![];
+    // writing into a Vec can't fail
+    let _ = rmpv::encode::write_value(&mut encoded, value);
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+    use pneumatic_core::encoding::deserialize_rmp_to;
+    use super::*;
+
+    fn map_keys(encoded: &[u8]) -> Vec<Value> {
+        let Value::Map(entries) = rmpv::decode::read_value(&mut &encoded[..]).unwrap()
+            else { panic!("not a map") };
+        entries.into_iter().map(|(key, _)| key).collect()
+    }
+
+    #[test]
+    fn orders_map_entries_by_encoded_key() {
+        let forward: Vec<(String, u32)> = (0..64).map(|i| (format!("key{i}"), i)).collect();
+        let first: HashMap<String, u32> = forward.iter().cloned().collect();
+        let second: HashMap<String, u32> = forward.iter().rev().cloned().collect();
+
+        let encoded = serialize_canonical(&first).unwrap();
+        assert_eq!(encoded, serialize_canonical(&second).unwrap());
+        let keys: Vec<Vec<u8>> = map_keys(&encoded).iter().map(encode).collect();
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(keys, sorted);
+        assert_eq!(deserialize_rmp_to::<HashMap<String, u32>>(&encoded).ok(), Some(first));
+    }
+
+    #[test]
+    fn orders_by_encoding_rather_than_value() {
+        // "b" is encoded shorter than "aa", so it sorts first despite being greater
+        let map = BTreeMap::from([("aa".to_string(), 1), ("b".to_string(), 2)]);
+        let keys = map_keys(&serialize_canonical(&map).unwrap());
+        assert_eq!(keys, vec![Value::from("b"), Value::from("aa")]);
+    }
+
+    #[test]
+    fn orders_nested_maps() {
+        let inner = |order: &[u32]| order.iter().map(|&i| (i, i.to_string())).collect::<HashMap<u32, String>>();
+        let first = HashMap::from([(1u32, vec![inner(&[3, 1, 2])]), (2, vec![inner(&[5, 4])])]);
+        let second = HashMap::from([(2u32, vec![inner(&[4, 5])]), (1, vec![inner(&[2, 3, 1])])]);
+
+        let encoded = serialize_canonical(&first).unwrap();
+        assert_eq!(encoded, serialize_canonical(&second).unwrap());
+        let Value::Map(entries) = rmpv::decode::read_value(&mut &encoded[..]).unwrap()
+            else { panic!("not a map") };
+        for (_, nested) in entries {
+            let Value::Array(maps) = nested else { panic!("not an array") };
+            let keys = map_keys(&encode(&maps[0]));
+            let mut sorted = keys.clone();
+            sorted.sort_by_key(encode);
+            assert_eq!(keys, sorted);
+        }
+        assert_eq!(deserialize_rmp_to::<HashMap<u32, Vec<HashMap<u32, String>>>>(&encoded).ok(), Some(first));
+    }
+
+    #[test]
+    fn normalizes_integer_widths() {
+        assert_eq!(serialize_canonical(&1u64).unwrap(), serialize_canonical(&1u8).unwrap());
+    }
+}
@@ -0,0 +1,84 @@
+use rocksdb::WriteBatchIteratorCf;
+
+// RocksDB's id for the default column family, which holds everything but namespaced data
+const DEFAULT_CF: u32 = 0;
+
+/// One write read back from a partition's WAL, with the sequence number RocksDB gave it.
+/// Values and merge operands are decoded, as get_data would return them; counters' and
+/// refcounts' are raw little-endian integers, as they're stored.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChangeRecord {
+    Put { sequence: u64, key: Vec<u8>, value: Vec<u8> },
+    Delete { sequence: u64, key: Vec<u8> },
+    /// A merge into the key's value (append_to_data, next_id, refcount changes), which takes
+    /// replaying through the same merge to apply.
+    Merge { sequence: u64, key: Vec<u8>, operand: Vec<u8> }
+}
+
+impl ChangeRecord {
+    pub fn sequence(&self) -> u64 {
+        match self {
+            ChangeRecord::Put { sequence, .. } => *sequence,
+            ChangeRecord::Delete { sequence, .. } => *sequence,
+            ChangeRecord::Merge { sequence, .. } => *sequence
+        }
+    }
+}
+
+/// Collects a WAL batch's writes, numbering them on from the batch's sequence number the way
+/// RocksDB does (one per write, whatever its column family). Only the default column family's
+/// writes are kept; the rest still take their sequence numbers. Values are left as stored.
+pub(crate) struct BatchRecords {
+    next_sequence: u64,
+    pub(crate) records: Vec<ChangeRecord>
+}
+
+impl BatchRecords {
+    pub(crate) fn new(sequence: u64) -> Self {
+        BatchRecords { next_sequence: sequence, records: vec![] }
+    }
+
+    // None for a write outside the default column family
+    fn take_sequence(&mut self, cf_id: u32) -> Option<u64> {
+        self.next_sequence += 1;
+        (cf_id == DEFAULT_CF).then_some(self.next_sequence - 1)
+    }
+}
+
+impl WriteBatchIteratorCf for BatchRecords {
+    fn put_cf(&mut self, cf_id: u32, key: &[u8], value: &[u8]) {
+        let Some(sequence) = self.take_sequence(cf_id) else { return };
+        self.records.push(ChangeRecord::Put { sequence, key: key.to_vec(), value: value.to_vec() });
+    }
+
+    fn delete_cf(&mut self, cf_id: u32, key: &[u8]) {
+        let Some(sequence) = self.take_sequence(cf_id) else { return };
+        self.records.push(ChangeRecord::Delete { sequence, key: key.to_vec() });
+    }
+
+    fn merge_cf(&mut self, cf_id: u32, key: &[u8], value: &[u8]) {
+        let Some(sequence) = self.take_sequence(cf_id) else { return };
+        self.records.push(ChangeRecord::Merge { sequence, key: key.to_vec(), operand: value.to_vec() });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_every_write_and_keeps_the_default_column_family() {
+        let mut records = BatchRecords::new(10);
+        records.put_cf(DEFAULT_CF, b"a", b"1");
+        records.put_cf(3, b"namespaced", b"2");
+        records.merge_cf(DEFAULT_CF, b"b", b"3");
+        records.delete_cf(3, b"namespaced");
+        records.delete_cf(DEFAULT_CF, b"a");
+
+        assert_eq!(records.records, vec![
+            ChangeRecord::Put { sequence: 10, key: b"a".to_vec(), value: b"1".to_vec() },
+            ChangeRecord::Merge { sequence: 12, key: b"b".to_vec(), operand: b"3".to_vec() },
+            ChangeRecord::Delete { sequence: 14, key: b"a".to_vec() }
+        ]);
+    }
+}
@@ -0,0 +1,64 @@
+// The namespace goes first with its length in front (u32, big-endian), then the id as-is.
+// Knowing where the namespace ends is enough to make the split unambiguous, and leaving the
+// id raw keeps a namespace's keys in id order, so scanning one yields its ids sorted.
+const LENGTH_SIZE: usize = 4;
+
+/// The store key for `id` within `namespace`. Different (namespace, id) pairs always give
+/// different keys, even where their concatenations match (e.g. "ab"/"c" and "a"/"bc").
+pub fn composite_key(namespace: &[u8], id: &[u8]) -> Vec<u8> {
+    let mut key = namespace_prefix(namespace);
+    key.extend_from_slice(id);
+    key
+}
+
+/// Splits a key made by [composite_key] back into its namespace and id, None if it isn't one.
+pub fn split_composite_key(key: &[u8]) -> Option<(&[u8], &[u8])> {
+    let length = key.get(..LENGTH_SIZE)?;
+    let length = u32::from_be_bytes(length.try_into().ok()?) as usize;
+    let id_start = LENGTH_SIZE.checked_add(length)?;
+    if key.len() < id_start { return None; }
+    Some((&key[LENGTH_SIZE..id_start], &key[id_start..]))
+}
+
+/// The prefix every key in `namespace` starts with, and no key in any other namespace does.
+pub fn namespace_prefix(namespace: &[u8]) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(LENGTH_SIZE + namespace.len());
+    prefix.extend_from_slice(&(namespace.len() as u32).to_be_bytes());
+    prefix.extend_from_slice(namespace);
+    prefix
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_back_what_it_joins() {
+        for (namespace, id) in [(&b"tokens"[..], &b"42"[..]), (b"", b"id"), (b"namespace", b""), (b"", b"")] {
+            let key = composite_key(namespace, id);
+            assert_eq!(split_composite_key(&key), Some((namespace, id)));
+            assert!(key.starts_with(&namespace_prefix(namespace)));
+        }
+    }
+
+    #[test]
+    fn keeps_namespaces_apart() {
+        assert_ne!(composite_key(b"ab", b"c"), composite_key(b"a", b"bc"));
+        assert!(!composite_key(b"ab", b"c").starts_with(&namespace_prefix(b"a")));
+    }
+
+    #[test]
+    fn orders_a_namespace_by_id() {
+        let (a, b) = (composite_key(b"ns", &[0, 1]), composite_key(b"ns", &[0, 2]));
+        assert!(a < b);
+    }
+
+    #[test]
+    fn rejects_malformed_keys() {
+        assert_eq!(split_composite_key(b""), None);
+        assert_eq!(split_composite_key(&[0, 0, 0]), None);
+        // claims a 5 byte namespace but has only 4
+        assert_eq!(split_composite_key(&[0, 0, 0, 5, b'a', b'b', b'c', b'd']), None);
+        assert_eq!(split_composite_key(&[0xFF, 0xFF, 0xFF, 0xFF]), None);
+    }
+}
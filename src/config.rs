@@ -0,0 +1,385 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+use serde::{ser, Serialize, Serializer, Deserialize};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DataConfig {
+    /// Maximum number of partition handles kept open at once. Once the limit is hit the
+    /// least-recently-used handle is flushed and closed, and reopened on its next access. A
+    /// handle still in use elsewhere (e.g. by a ScanStream) only closes once that's done, and
+    /// an access before then takes the same handle back.
+    pub max_open_partitions: u64,
+
+    /// Maximum number of entries in each of the token and data caches, None for no limit
+    /// (entries then only leave the cache by going idle).
+    pub cache_capacity: Option<u64>,
+    /// Bounds the caches' combined size, in MB, None for no bound. Every partition caches into
+    /// the same process-wide caches, so this holds however many partitions are open. Entries
+    /// are weighed by key and value size (Tokens by their inline size) and the coldest ones
+    /// evicted once over; half the budget goes to Tokens and half to data. Replaces
+    /// cache_capacity when set. A value grown in place through its cached lock keeps the
+    /// weight it was cached with.
+    pub cache_memory_budget_mb: Option<u64>,
+    /// Caps how many writes per second (across both caches) insert their value into the
+    /// cache, None for no cap. Writes over the cap still go to the store but leave the key
+    /// uncached, so its next read loads it from disk; this bounds eviction work during write
+    /// bursts. Reads always cache what they load.
+    pub cache_insert_rate_limit: Option<u64>,
+
+    /// WAL settings. Obsolete WAL files (those whose writes have all been flushed to SSTs) are
+    /// normally deleted right away; a ttl and/or size limit archives them instead, bounding how
+    /// much disk they take while keeping recent history around (e.g. for replication). None
+    /// of this changes when a write becomes durable: that's still the WAL append (plus its sync,
+    /// if the write asks for one), so these settings can be tuned independently of fsync cost.
+    pub wal_ttl_seconds: Option<u64>,
+    pub wal_size_limit_mb: Option<u64>,
+    /// Reuses this many spent WAL files instead of creating new ones, avoiding lots of small
+    /// files and allocation cost. RocksDB ignores it while WAL archiving (ttl/size) is on.
+    pub recycle_log_file_num: Option<usize>,
+    /// Syncs every open partition's WAL to disk this often from a background thread, None
+    /// (the default) to leave it to RocksDB. Writes aren't synced as they're made, so a machine
+    /// crash loses whatever hadn't reached disk yet; this bounds that to about one interval
+    /// without paying for a sync on every write. SafeDataProvider::shutdown stops the thread
+    /// after one last pass.
+    pub auto_flush_interval: Option<Duration>,
+
+    /// Makes identical data values cached under different keys share one buffer, at the cost
+    /// of hashing every value that enters the cache. Since the buffer is shared, writing
+    /// through one key's cached lock changes what the other keys see too, so only turn this on
+    /// if cached data is never mutated in place.
+    pub intern_cache_values: bool,
+
+    /// Caches data values as plain immutable `Arc<Vec<u8>>`s instead of `Arc<RwLock<..>>`s,
+    /// so reads through get_data_shared (and typed reads) don't take a lock. Saves replace the
+    /// cached Arc instead of writing into it. get_data keeps working, but hands every caller a
+    /// lock around its own copy, so writing through it no longer changes what others read;
+    /// leave this off if anything mutates cached data in place. intern_cache_values doesn't
+    /// apply to this cache.
+    pub immutable_data_cache: bool,
+
+    /// Collects RocksDB statistics, which write_amplification is computed from. Costs a few
+    /// percent of throughput.
+    pub enable_statistics: bool,
+    /// Samples one in this many key lock acquisitions (the per-key locks held across a store
+    /// access, and reads of cached entries' locks) to count the ones that had to wait, for
+    /// SafeDataProvider::contended_keys. None (the default) doesn't track contention at all.
+    pub lock_contention_sample_rate: Option<u32>,
+
+    /// Has RocksDB check the store more aggressively (e.g. file checksums on open) and fail
+    /// rather than carry on past corruption it notices.
+    pub paranoid_checks: bool,
+    /// Runs verify_partition whenever a partition is opened and refuses to open it if that
+    /// fails. Reads the whole partition, so opens get much slower on big partitions, but a
+    /// node coming back from a crash won't serve traffic from damaged data.
+    pub verify_on_open: bool,
+    /// Reads every single-key save (save_token, save_data and their variants) straight back
+    /// from the store, bypassing the caches, and fails it with WriteVerificationFailed unless
+    /// it comes back exactly as written. The write itself has already happened by then. Doubles
+    /// the cost of a save, so it's meant for chasing suspected corruption, not for normal
+    /// running. Batches, bulk loads and merges aren't checked.
+    pub verify_writes: bool,
+
+    /// Once a write to a partition fails with DataError::OutOfSpace, rejects every further
+    /// write to it with OutOfSpace straight away, without touching the store, while reads keep
+    /// being served. Without it writes keep going to RocksDB, which has usually stopped writes
+    /// itself by then and fails them with the same error. Either way the partition takes
+    /// writes again after SafeDataProvider::reopen_partition, once space has been freed.
+    pub read_only_on_out_of_space: bool,
+
+    /// Size of a block cache shared by every partition, None for RocksDB's default of a
+    /// separate small cache per partition. Fixed by the first partition opened.
+    pub block_cache_mb: Option<usize>,
+
+    /// Maximum number of SST files each partition keeps open, -1 (the default) for no limit.
+    /// With a limit, files outside RocksDB's table cache have to be reopened (and their index
+    /// and filter blocks re-read) when a read needs them, so reads get slower in exchange for
+    /// fewer file descriptors, which matters once many partitions are open at once. Can be
+    /// overridden per partition.
+    pub max_open_files: i32,
+
+    /// Settings for individual partitions, keyed by partition id. Partitions not listed here
+    /// use PartitionConfig::default().
+    pub partitions: HashMap<String, PartitionConfig>,
+}
+
+impl Default for DataConfig {
+    fn default() -> Self {
+        DataConfig {
+            max_open_partitions: 256,
+            cache_capacity: None,
+            cache_memory_budget_mb: None,
+            cache_insert_rate_limit: None,
+            wal_ttl_seconds: None,
+            wal_size_limit_mb: None,
+            recycle_log_file_num: None,
+            auto_flush_interval: None,
+            intern_cache_values: false,
+            immutable_data_cache: false,
+            enable_statistics: false,
+            lock_contention_sample_rate: None,
+            paranoid_checks: false,
+            verify_on_open: false,
+            verify_writes: false,
+            read_only_on_out_of_space: false,
+            block_cache_mb: None,
+            max_open_files: -1,
+            partitions: HashMap::new()
+        }
+    }
+}
+
+impl DataConfig {
+    pub(crate) fn partition(&self, partition_id: &str) -> PartitionConfig {
+        self.partitions.get(partition_id).cloned().unwrap_or_default()
+    }
+
+    pub(crate) fn effective(&self, partition_id: &str, cache_time_to_idle: Duration) -> EffectiveConfig {
+        let partition = self.partition(partition_id);
+        let read_only = partition.secondary_path.is_some();
+        EffectiveConfig {
+            partition_id: partition_id.to_string(),
+            cache_time_to_idle,
+            cache_capacity: self.cache_capacity,
+            cache_memory_budget_mb: self.cache_memory_budget_mb,
+            cache_insert_rate_limit: self.cache_insert_rate_limit,
+            intern_cache_values: self.intern_cache_values,
+            immutable_data_cache: self.immutable_data_cache,
+            max_open_partitions: self.max_open_partitions,
+            routed: crate::hooks::router(partition_id).is_some(),
+            compaction_filter: crate::hooks::compaction_filter(partition_id).is_some(),
+            read_only,
+            secondary_path: partition.secondary_path,
+            max_replica_lag: partition.max_replica_lag,
+            wal_ttl_seconds: self.wal_ttl_seconds,
+            wal_size_limit_mb: self.wal_size_limit_mb,
+            recycle_log_file_num: self.recycle_log_file_num,
+            auto_flush_interval: self.auto_flush_interval,
+            enable_statistics: self.enable_statistics,
+            lock_contention_sample_rate: self.lock_contention_sample_rate,
+            paranoid_checks: self.paranoid_checks,
+            verify_on_open: self.verify_on_open,
+            verify_writes: self.verify_writes,
+            read_only_on_out_of_space: self.read_only_on_out_of_space,
+            block_cache_mb: self.block_cache_mb,
+            max_open_files: match read_only {
+                true => -1,
+                false => partition.max_open_files.unwrap_or(self.max_open_files)
+            },
+            allow_mmap_reads: partition.allow_mmap_reads,
+            block_size: partition.block_size,
+            zstd_dictionary_ids: partition.zstd_dictionaries.iter().map(|dictionary| dictionary.id).collect(),
+            zstd_level: partition.zstd_level,
+            encrypted: partition.encryption_key.is_some(),
+            canonical_tokens: partition.canonical_tokens,
+            lazy_tokens: partition.lazy_tokens,
+            append_only: partition.append_only,
+            checksum_on_write: partition.checksum_on_write,
+            checksum_on_read: partition.checksum_on_read
+        }
+    }
+}
+
+/// The settings a partition actually runs with: the provider-wide config with the
+/// partition's overrides (or PartitionConfig's defaults) applied, plus what's fixed in code or
+/// registered at runtime. Keys are left out; only whether there is one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EffectiveConfig {
+    pub partition_id: String,
+
+    /// How long a cache entry lives without being read. Shared by every partition.
+    pub cache_time_to_idle: Duration,
+    pub cache_capacity: Option<u64>,
+    pub cache_memory_budget_mb: Option<u64>,
+    pub cache_insert_rate_limit: Option<u64>,
+    pub intern_cache_values: bool,
+    pub immutable_data_cache: bool,
+    pub max_open_partitions: u64,
+
+    /// Whether a router splits the partition between backends (see set_router); otherwise
+    /// it's all in RocksDB.
+    pub routed: bool,
+    /// Whether a compaction filter is registered for the partition.
+    pub compaction_filter: bool,
+    /// Whether the partition opens as a read-only secondary.
+    pub read_only: bool,
+    pub secondary_path: Option<String>,
+    pub max_replica_lag: Option<u64>,
+
+    pub wal_ttl_seconds: Option<u64>,
+    pub wal_size_limit_mb: Option<u64>,
+    pub recycle_log_file_num: Option<usize>,
+    pub auto_flush_interval: Option<Duration>,
+    pub enable_statistics: bool,
+    pub lock_contention_sample_rate: Option<u32>,
+    pub paranoid_checks: bool,
+    pub verify_on_open: bool,
+    pub verify_writes: bool,
+    pub read_only_on_out_of_space: bool,
+    pub block_cache_mb: Option<usize>,
+    pub max_open_files: i32,
+    pub allow_mmap_reads: bool,
+    pub block_size: Option<usize>,
+
+    /// Ids of the partition's zstd dictionaries, the last one being what new values are
+    /// compressed with. Empty if values aren't compressed.
+    pub zstd_dictionary_ids: Vec<u32>,
+    pub zstd_level: i32,
+    pub encrypted: bool,
+    pub canonical_tokens: bool,
+    pub lazy_tokens: bool,
+    pub append_only: bool,
+    pub checksum_on_write: bool,
+    pub checksum_on_read: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PartitionConfig {
+    /// Dictionaries to zstd-compress this partition's values with, which works far better than
+    /// plain compression for many small, similarly shaped values. New values are compressed
+    /// with the last dictionary listed; retired ones must stay listed for as long as values
+    /// written with them exist. Dictionaries can be trained with train_zstd_dictionary.
+    pub zstd_dictionaries: Vec<ZstdDictionary>,
+    pub zstd_level: i32,
+
+    /// Encrypts this partition's values at rest with their own key, so a leaked key only
+    /// exposes one partition. Opening the partition fails if the key can't decrypt the values
+    /// already in it. Keys only cover values; keys (and column family names) are stored as-is.
+    pub encryption_key: Option<EncryptionKey>,
+
+    /// Serializes Tokens with serialize_canonical, so equal Tokens are stored (and returned
+    /// by get_token_raw) as byte-identical values on every node, e.g. for hashing them in
+    /// consensus. Costs a decode and re-encode per save. Only covers Tokens the provider
+    /// serializes itself: save_token_bytes and plain data are stored exactly as given.
+    pub canonical_tokens: bool,
+
+    /// Caches this partition's Tokens serialized, deserializing each one only when it's first
+    /// read through (see [crate::LazyToken]), for partitions that mostly check Tokens exist
+    /// rather than look inside them. get_token still returns them deserialized, so only
+    /// get_token_lazy callers save anything.
+    pub lazy_tokens: bool,
+
+    /// Makes the partition append-only, e.g. for an audit log: saving to a key that already
+    /// has a value fails with DataError::AlreadyExists, and deleting fails with
+    /// OperationNotAllowed, whether singly, in a batch or through a bulk load. append_to_data,
+    /// next_id and other merges still work, as do the provider's own bookkeeping keys (rings,
+    /// refcounts). Values that expire are hidden but not deleted. Every save pays for a read
+    /// of the key first. A compaction filter can still remove entries, so don't register one
+    /// that does. Keys a router keeps in memory are covered too.
+    pub append_only: bool,
+
+    /// Stores a crc32 of every value written, and checks it whenever a value is read. The two
+    /// are independent: values written without a checksum (or before checksums were turned on)
+    /// are read as usual either way, so a rarely-written, hot-read partition can pay the write
+    /// cost only, and a write-heavy low-value one can skip both. RocksDB's own block checksums
+    /// are unaffected; these cover a value end to end, e.g. against corruption in the cache layer
+    /// or a bad copy between partitions.
+    pub checksum_on_write: bool,
+    pub checksum_on_read: bool,
+
+    /// Opens this partition as a read-only secondary of the primary instance whose data
+    /// directory is the partition's path, typically run by another process. The secondary
+    /// keeps its own info logs under `secondary_path` (which must differ per secondary) and
+    /// sees the primary's data as of when it opened, until SafeDataProvider::catch_up replays
+    /// what the primary has written since; reads are stale by however long ago that was.
+    /// Writes fail with DataError::ReadOnly, and max_open_files is forced to -1, which
+    /// RocksDB requires of secondaries.
+    pub secondary_path: Option<String>,
+    /// Has a secondary's single-key reads (get_token, get_data and their variants, cached or
+    /// not) fail with DataError::ReplicaBehind while it's more than this many sequence numbers
+    /// (roughly, key writes) behind its primary, None for no bound. Reads go by the lag last
+    /// measured by SafeDataProvider::replica_lag, which catch_up resets to 0, so something has
+    /// to call replica_lag regularly for the bound to mean anything; scans aren't checked.
+    pub max_replica_lag: Option<u64>,
+
+    /// Overrides DataConfig::max_open_files for this partition.
+    pub max_open_files: Option<i32>,
+
+    /// Reads SST files through mmap instead of pread, saving a syscall and a copy per block.
+    /// Pays off for read-heavy, mostly static partitions whose files fit in the page cache;
+    /// otherwise reads turn into page faults that stall the reading thread. A file truncated
+    /// or deleted underneath the mapping (e.g. by something outside RocksDB) makes reads crash
+    /// the process with SIGBUS instead of returning an error, and mapped pages count toward
+    /// the process' resident memory.
+    pub allow_mmap_reads: bool,
+
+    /// Size in bytes of the data blocks this partition's SST files are cut into, None for
+    /// RocksDB's default of 4 KiB. A block is the unit of reading, caching and compression:
+    /// smaller blocks mean a point lookup reads (and caches) less it doesn't need, while larger
+    /// ones compress better and make scans cheaper, at the cost of a bigger index and more
+    /// bytes read per lookup. Only applies to files written after it's changed.
+    pub block_size: Option<usize>,
+}
+
+impl Default for PartitionConfig {
+    fn default() -> Self {
+        PartitionConfig {
+            zstd_dictionaries: vec![],
+            zstd_level: 3,
+            encryption_key: None,
+            canonical_tokens: false,
+            lazy_tokens: false,
+            append_only: false,
+            checksum_on_write: false,
+            checksum_on_read: false,
+            secondary_path: None,
+            max_replica_lag: None,
+            max_open_files: None,
+            allow_mmap_reads: false,
+            block_size: None
+        }
+    }
+}
+
+/// A 32-byte ChaCha20-Poly1305 key, given directly or by reference. Debug output redacts key
+/// bytes, and serializing a config refuses to write them out, so the key can't leak into logs
+/// or config dumps; configs meant to be written somewhere should use Env.
+#[derive(Clone, Deserialize)]
+pub enum EncryptionKey {
+    Bytes(Vec<u8>),
+    /// Name of an environment variable holding the key as 64 hex characters.
+    Env(String),
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncryptionKey::Bytes(_) => write!(f, "Bytes(<redacted>)"),
+            EncryptionKey::Env(name) => f.debug_tuple("Env").field(name).finish()
+        }
+    }
+}
+
+impl Serialize for EncryptionKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            EncryptionKey::Bytes(_) => Err(ser::Error::custom("encryption key bytes aren't serialized, use EncryptionKey::Env")),
+            EncryptionKey::Env(name) => serializer.serialize_newtype_variant("EncryptionKey", 1, "Env", name)
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ZstdDictionary {
+    /// Recorded in the header of every value compressed with this dictionary, so it must be
+    /// non-zero and never reused for different dictionary bytes.
+    pub id: u32,
+    pub bytes: Vec<u8>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_redacts_key_bytes() {
+        let key = EncryptionKey::Bytes(vec![0xAB; 32]);
+        assert_eq!(format!("{key:?}"), "Bytes(<redacted>)");
+
+        let config = PartitionConfig { encryption_key: Some(key), ..PartitionConfig::default() };
+        assert!(!format!("{config:?}").contains("171"));
+        assert_eq!(format!("{:?}", EncryptionKey::Env("KEY".to_string())), "Env(\"KEY\")");
+    }
+}
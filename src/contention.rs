@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LockResult, Mutex, MutexGuard, OnceLock, RwLock, RwLockReadGuard, TryLockError};
+use crate::SafeDataProvider;
+
+// Past this many keys a partition's counts are halved and those reaching zero dropped, so
+// keys contended once long ago make room for the ones contended now.
+const MAX_TRACKED_KEYS: usize = 4096;
+
+static ACQUISITIONS: AtomicU64 = AtomicU64::new(0);
+static CONTENDED: OnceLock<Mutex<HashMap<String, HashMap<Vec<u8>, u64>>>> = OnceLock::new();
+
+/// Locks `lock` on behalf of `key`, counting the acquisition against the key if it's sampled
+/// and has to wait.
+pub(crate) fn lock<'a, T>(lock: &'a Mutex<T>, key: &[u8], partition_id: &str) -> MutexGuard<'a, T> {
+    if sampled() {
+        match lock.try_lock() {
+            Err(TryLockError::WouldBlock) => record(key, partition_id),
+            Err(TryLockError::Poisoned(poisoned)) => return poisoned.into_inner(),
+            Ok(guard) => return guard
+        }
+    }
+    lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// As [lock], for a read lock on a cached entry.
+pub(crate) fn read<'a, T>(lock: &'a RwLock<T>, key: &[u8], partition_id: &str) -> LockResult<RwLockReadGuard<'a, T>> {
+    if sampled() {
+        match lock.try_read() {
+            Err(TryLockError::WouldBlock) => record(key, partition_id),
+            Err(TryLockError::Poisoned(poisoned)) => return Err(poisoned),
+            Ok(guard) => return Ok(guard)
+        }
+    }
+    lock.read()
+}
+
+pub(crate) fn contended_keys(partition_id: &str, n: usize) -> Vec<(Vec<u8>, u64)> {
+    let contended = contended().lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let Some(keys) = contended.get(partition_id)
+        else { return vec![] };
+
+    let mut keys: Vec<(Vec<u8>, u64)> = keys.iter().map(|(key, count)| (key.clone(), *count)).collect();
+    keys.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    keys.truncate(n);
+    keys
+}
+
+pub(crate) fn reset() {
+    contended().lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clear();
+}
+
+fn sampled() -> bool {
+    let Some(sample_rate) = SafeDataProvider::get_config().lock_contention_sample_rate
+        else { return false };
+    ACQUISITIONS.fetch_add(1, Ordering::Relaxed) % sample_rate.max(1) as u64 == 0
+}
+
+fn record(key: &[u8], partition_id: &str) {
+    let mut contended = contended().lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let keys = contended.entry(partition_id.to_string()).or_default();
+    *keys.entry(key.to_vec()).or_default() += 1;
+    if keys.len() <= MAX_TRACKED_KEYS { return; }
+
+    keys.retain(|_, count| {
+        *count /= 2;
+        *count > 0
+    });
+}
+
+fn contended() -> &'static Mutex<HashMap<String, HashMap<Vec<u8>, u64>>> {
+    CONTENDED.get_or_init(|| Mutex::new(HashMap::new()))
+}
@@ -0,0 +1,41 @@
+use crate::{DataError, Db, SafeDataProvider, ScanStream};
+
+/// A contract's own keyspace within a partition, backed by a column family named after the
+/// contract. The column family is created on the first save; reads of a contract that has
+/// never saved anything just find nothing. Contract data bypasses the provider caches.
+pub struct ContractStore {
+    partition_id: String,
+    column_family: String
+}
+
+impl ContractStore {
+    pub(crate) fn new(partition_id: &str, contract_id: &str) -> Self {
+        ContractStore {
+            partition_id: partition_id.to_string(),
+            column_family: format!("contract:{contract_id}")
+        }
+    }
+
+    pub fn get(&self, key: &Vec<u8>) -> Result<Vec<u8>, DataError> {
+        SafeDataProvider::observe("contract_get", &self.partition_id, || {
+            SafeDataProvider::get_db(&self.partition_id)?.get_in(&self.column_family, key)
+        })
+    }
+
+    pub fn save(&self, key: &Vec<u8>, data: &[u8]) -> Result<(), DataError> {
+        SafeDataProvider::observe("contract_save", &self.partition_id, || {
+            SafeDataProvider::get_db(&self.partition_id)?.save_in(&self.column_family, key, data)
+        })
+    }
+
+    /// Streams every entry of the contract, in key order, from a snapshot (see [ScanStream]).
+    pub fn scan(&self) -> Result<ScanStream<(Vec<u8>, Vec<u8>)>, DataError> {
+        SafeDataProvider::observe("contract_scan", &self.partition_id, || {
+            let db = SafeDataProvider::get_db(&self.partition_id)?;
+            let column_family = self.column_family.clone();
+            Ok(ScanStream::new("contract_scan", &self.partition_id, move |emit| {
+                db.scan_in(&column_family, None, None, &mut |key, value| emit((key.to_vec(), value.to_vec())))
+            }))
+        })
+    }
+}
@@ -0,0 +1,36 @@
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+use pneumatic_core::tokens::Token;
+use crate::{hooks, DataError, SafeDataProvider};
+
+/// Async reads bounded by a caller's deadline. The read runs on tokio's blocking pool and
+/// RocksDB can't cancel it, so when the deadline passes the caller gets DataError::Timeout
+/// right away while the read finishes (and may still warm the cache) in the background.
+impl SafeDataProvider {
+    pub async fn get_token_before(key: Vec<u8>, partition_id: String, deadline: Instant)
+                                  -> Result<Arc<RwLock<Token>>, DataError> {
+        let observed_id = partition_id.clone();
+        run_before(deadline, "get_token", observed_id, move || Self::get_token(&key, &partition_id)).await
+    }
+
+    pub async fn get_data_before(key: Vec<u8>, partition_id: String, deadline: Instant)
+                                 -> Result<Arc<RwLock<Vec<u8>>>, DataError> {
+        let observed_id = partition_id.clone();
+        run_before(deadline, "get_data", observed_id, move || Self::get_data(&key, &partition_id)).await
+    }
+}
+
+async fn run_before<T, F>(deadline: Instant, op: &'static str, partition_id: String, read: F)
+                          -> Result<T, DataError>
+    where T: Send + 'static, F: FnOnce() -> Result<T, DataError> + Send + 'static {
+    let task = tokio::task::spawn_blocking(read);
+    match tokio::time::timeout_at(tokio::time::Instant::from_std(deadline), task).await {
+        Err(_elapsed) => {
+            let err = DataError::Timeout;
+            hooks::report_error(&err, &partition_id, op);
+            Err(err)
+        },
+        Ok(Err(join_err)) => Err(DataError::FromStore(join_err.to_string())),
+        Ok(Ok(result)) => result
+    }
+}
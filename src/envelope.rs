@@ -0,0 +1,531 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use zstd::bulk::{Compressor, Decompressor};
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
+use crate::config::{EncryptionKey, PartitionConfig};
+use crate::DataError;
+
+// Values that need a header start with 0xC1 (a byte MessagePack never uses) plus two more, so
+// they're told apart from header-less values, which is what everything else is stored as.
+const MAGIC: [u8; 3] = [0xC1, b'P', b'D'];
+
+const COMPRESSED: u8 = 0b0000_0001;
+const ENCRYPTED: u8 = 0b0000_0010;
+const CHECKSUMMED: u8 = 0b0000_0100;
+const EXPIRES: u8 = 0b0000_1000;
+const TYPE_TAGGED: u8 = 0b0001_0000;
+const KNOWN_FLAGS: u8 = COMPRESSED | ENCRYPTED | CHECKSUMMED | EXPIRES | TYPE_TAGGED;
+
+// Auto compresses a value if a sample of it shrinks by at least a tenth at a fast level
+const AUTO_SAMPLE_LEN: usize = 4096;
+const AUTO_MIN_LEN: usize = 64;
+const AUTO_SAMPLE_LEVEL: i32 = 1;
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+/// Whether to compress a value saved with save_data_with_compression, overriding what the
+/// partition's config would do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompressionHint {
+    /// Stored uncompressed, e.g. for media or other already compressed data.
+    None,
+    /// Compressed with zstd, using the partition's active dictionary if it has one.
+    Zstd,
+    /// Compressed like Zstd if a trial compression of a sample of the value says it's worth
+    /// it, otherwise stored uncompressed.
+    Auto
+}
+
+/// Encodes values on their way into a partition and decodes them on the way out, according to
+/// the partition's config. Values that don't need a header are stored as-is, so a partition
+/// without any header features configured reads and writes plain bytes.
+pub(crate) struct ValueCodec {
+    zstd_level: i32,
+    active_dictionary: Option<u32>,
+    encoders: HashMap<u32, EncoderDictionary<'static>>,
+    decoders: HashMap<u32, DecoderDictionary<'static>>,
+    cipher: Option<Cipher>,
+    checksum_on_write: bool,
+    checksum_on_read: bool
+}
+
+struct Cipher {
+    cipher: ChaCha20Poly1305,
+    // also authenticated with every value, so a value can only be decrypted under its own key
+    key: Vec<u8>
+}
+
+impl ValueCodec {
+    pub(crate) fn new(config: &PartitionConfig) -> Result<Self, DataError> {
+        let mut encoders = HashMap::new();
+        let mut decoders = HashMap::new();
+        for dictionary in &config.zstd_dictionaries {
+            if dictionary.id == 0 {
+                return Err(DataError::FromStore("zstd dictionary id 0 is reserved".to_string()));
+            }
+
+            encoders.insert(dictionary.id, EncoderDictionary::copy(&dictionary.bytes, config.zstd_level));
+            decoders.insert(dictionary.id, DecoderDictionary::copy(&dictionary.bytes));
+        }
+
+        let cipher = match &config.encryption_key {
+            None => None,
+            Some(key) => Some(Cipher::new(resolve_key(key)?)?)
+        };
+
+        Ok(ValueCodec {
+            zstd_level: config.zstd_level,
+            active_dictionary: config.zstd_dictionaries.last().map(|dictionary| dictionary.id),
+            encoders,
+            decoders,
+            cipher,
+            checksum_on_write: config.checksum_on_write,
+            checksum_on_read: config.checksum_on_read
+        })
+    }
+
+    pub(crate) fn is_encrypting(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    pub(crate) fn encode(&self, data: &[u8]) -> Result<Vec<u8>, DataError> {
+        self.encode_until(data, None)
+    }
+
+    /// Like encode, recording `expires_at` (if any) in the header.
+    pub(crate) fn encode_until(&self, data: &[u8], expires_at: Option<SystemTime>) -> Result<Vec<u8>, DataError> {
+        let header = Header { expires_at: expires_at.map(to_millis), ..Header::default() };
+        self.encode_with(data, self.active_dictionary, header)
+    }
+
+    /// Like encode, recording `type_tag` in the header.
+    pub(crate) fn encode_tagged(&self, data: &[u8], type_tag: &str) -> Result<Vec<u8>, DataError> {
+        if type_tag.len() > u16::MAX as usize {
+            return Err(DataError::SerializationError(format!("type tags can't be longer than {} bytes", u16::MAX)));
+        }
+
+        let header = Header { type_tag: Some(type_tag.to_string()), ..Header::default() };
+        self.encode_with(data, self.active_dictionary, header)
+    }
+
+    /// Like encode, compressing as `hint` says rather than as the partition's config does.
+    /// Plain zstd (dictionary id 0) is used when the partition has no dictionaries.
+    pub(crate) fn encode_hinted(&self, data: &[u8], hint: CompressionHint) -> Result<Vec<u8>, DataError> {
+        let dictionary_id = self.active_dictionary.unwrap_or(0);
+        let compress_with = match hint {
+            CompressionHint::None => None,
+            CompressionHint::Zstd => Some(dictionary_id),
+            CompressionHint::Auto => worth_compressing(data).then_some(dictionary_id)
+        };
+        self.encode_with(data, compress_with, Header::default())
+    }
+
+    // `header` comes in with whatever isn't derived from the payload already filled in
+    fn encode_with(&self, data: &[u8], compress_with: Option<u32>, mut header: Header) -> Result<Vec<u8>, DataError> {
+        let mut payload = Cow::Borrowed(data);
+        if let Some(dictionary_id) = compress_with {
+            payload = Cow::Owned(self.compress(data, dictionary_id)?);
+            header.compression = Some(Compression { dictionary_id, original_len: data.len() as u32 });
+        }
+        if let Some(cipher) = &self.cipher {
+            let (nonce, ciphertext) = cipher.encrypt(&payload)?;
+            header.nonce = Some(nonce);
+            payload = Cow::Owned(ciphertext);
+        }
+        if self.checksum_on_write { header.checksum = Some(crc32fast::hash(&payload)); }
+
+        // a header-less value that happens to start with the magic gets an empty header, so
+        // it can't be mistaken for an encoded one
+        if header.is_empty() && !data.starts_with(&MAGIC) { return Ok(data.to_vec()); }
+
+        let mut stored = Vec::with_capacity(payload.len() + 16);
+        header.write(&mut stored);
+        stored.extend_from_slice(&payload);
+        Ok(stored)
+    }
+
+    pub(crate) fn decode<'a>(&self, stored: &'a [u8]) -> Result<Cow<'a, [u8]>, DataError> {
+        if !stored.starts_with(&MAGIC) { return Ok(Cow::Borrowed(stored)); }
+
+        let (header, payload) = Header::read(stored)?;
+        if let (true, Some(checksum)) = (self.checksum_on_read, header.checksum) {
+            if crc32fast::hash(payload) != checksum { return Err(DataError::ChecksumMismatch); }
+        }
+
+        let mut payload = Cow::Borrowed(payload);
+        if let Some(nonce) = &header.nonce {
+            let Some(cipher) = &self.cipher
+                else { return Err(DataError::DecryptionFailed) };
+            payload = Cow::Owned(cipher.decrypt(nonce, &payload)?);
+        }
+
+        match header.compression {
+            None => Ok(payload),
+            Some(compression) => self.decompress(&payload, &compression).map(Cow::Owned)
+        }
+    }
+
+    /// When a stored value expires, if it was saved with an expiry.
+    pub(crate) fn expiry(&self, stored: &[u8]) -> Result<Option<SystemTime>, DataError> {
+        if !stored.starts_with(&MAGIC) { return Ok(None); }
+        let (header, _) = Header::read(stored)?;
+        Ok(header.expires_at.map(|millis| UNIX_EPOCH + Duration::from_millis(millis)))
+    }
+
+    /// The type tag a stored value was saved with, if any.
+    pub(crate) fn type_tag(&self, stored: &[u8]) -> Result<Option<String>, DataError> {
+        if !stored.starts_with(&MAGIC) { return Ok(None); }
+        let (header, _) = Header::read(stored)?;
+        Ok(header.type_tag)
+    }
+
+    pub(crate) fn decode_owned(&self, stored: Vec<u8>) -> Result<Vec<u8>, DataError> {
+        if !stored.starts_with(&MAGIC) { return Ok(stored); }
+        self.decode(&stored).map(|data| data.into_owned())
+    }
+
+    fn compress(&self, data: &[u8], dictionary_id: u32) -> Result<Vec<u8>, DataError> {
+        let compressed = match self.encoders.get(&dictionary_id) {
+            Some(dictionary) => Compressor::with_prepared_dictionary(dictionary)
+                .and_then(|mut compressor| compressor.compress(data)),
+            None => zstd::bulk::compress(data, self.zstd_level)
+        };
+        compressed.map_err(|err| DataError::SerializationError(err.to_string()))
+    }
+
+    fn decompress(&self, payload: &[u8], compression: &Compression) -> Result<Vec<u8>, DataError> {
+        let capacity = compression.original_len as usize;
+        let decompressed = match compression.dictionary_id {
+            0 => zstd::bulk::decompress(payload, capacity),
+            id => {
+                let Some(dictionary) = self.decoders.get(&id)
+                    else { return Err(DataError::DeserializationError(format!("unknown zstd dictionary {id}"))) };
+                Decompressor::with_prepared_dictionary(dictionary)
+                    .and_then(|mut decompressor| decompressor.decompress(payload, capacity))
+            }
+        };
+        decompressed.map_err(|err| DataError::DeserializationError(err.to_string()))
+    }
+}
+
+impl Cipher {
+    fn new(key: Vec<u8>) -> Result<Self, DataError> {
+        if key.len() != KEY_LEN {
+            return Err(DataError::FromStore(format!("encryption keys must be {KEY_LEN} bytes")));
+        }
+
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+        Ok(Cipher { cipher, key })
+    }
+
+    fn encrypt(&self, plaintext: &[u8]) -> Result<([u8; NONCE_LEN], Vec<u8>), DataError> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload { msg: plaintext, aad: &self.key };
+        match self.cipher.encrypt(&nonce, payload) {
+            Err(err) => Err(DataError::SerializationError(err.to_string())),
+            Ok(ciphertext) => {
+                let mut nonce_bytes = [0; NONCE_LEN];
+                nonce_bytes.copy_from_slice(&nonce);
+                Ok((nonce_bytes, ciphertext))
+            }
+        }
+    }
+
+    fn decrypt(&self, nonce: &[u8; NONCE_LEN], ciphertext: &[u8]) -> Result<Vec<u8>, DataError> {
+        let payload = Payload { msg: ciphertext, aad: &self.key };
+        self.cipher.decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| DataError::DecryptionFailed)
+    }
+}
+
+fn resolve_key(key: &EncryptionKey) -> Result<Vec<u8>, DataError> {
+    match key {
+        EncryptionKey::Bytes(bytes) => Ok(bytes.clone()),
+        EncryptionKey::Env(name) => {
+            let Ok(hex) = std::env::var(name)
+                else { return Err(DataError::FromStore(format!("encryption key variable {name} isn't set"))) };
+            decode_hex(hex.trim())
+                .ok_or_else(|| DataError::FromStore(format!("encryption key variable {name} isn't valid hex")))
+        }
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 { return None; }
+    (0..hex.len()).step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect()
+}
+
+fn worth_compressing(data: &[u8]) -> bool {
+    if data.len() < AUTO_MIN_LEN { return false; }
+    let sample = &data[..data.len().min(AUTO_SAMPLE_LEN)];
+    match zstd::bulk::compress(sample, AUTO_SAMPLE_LEVEL) {
+        Err(_) => false,
+        Ok(compressed) => compressed.len() * 10 <= sample.len() * 9
+    }
+}
+
+// times before the epoch are as good as expired
+fn to_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64)
+}
+
+// Header layout: MAGIC, a flags byte, then the fields of each set flag in flag order.
+//   COMPRESSED: dictionary id (u32 LE, 0 = none), uncompressed length (u32 LE)
+//   ENCRYPTED: nonce (12 bytes); the payload is then the ciphertext of the (compressed) value
+//   CHECKSUMMED: crc32 of the payload as stored (u32 LE), so it can be checked before decrypting
+//   EXPIRES: expiry time in milliseconds since the Unix epoch (u64 LE)
+//   TYPE_TAGGED: length of the tag (u16 LE), then the tag as UTF-8
+#[derive(Default)]
+struct Header {
+    compression: Option<Compression>,
+    nonce: Option<[u8; NONCE_LEN]>,
+    checksum: Option<u32>,
+    expires_at: Option<u64>,
+    type_tag: Option<String>
+}
+
+struct Compression {
+    dictionary_id: u32,
+    original_len: u32
+}
+
+impl Header {
+    fn is_empty(&self) -> bool {
+        self.compression.is_none() && self.nonce.is_none() && self.checksum.is_none() && self.expires_at.is_none()
+            && self.type_tag.is_none()
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        let mut flags = 0;
+        if self.compression.is_some() { flags |= COMPRESSED; }
+        if self.nonce.is_some() { flags |= ENCRYPTED; }
+        if self.checksum.is_some() { flags |= CHECKSUMMED; }
+        if self.expires_at.is_some() { flags |= EXPIRES; }
+        if self.type_tag.is_some() { flags |= TYPE_TAGGED; }
+
+        out.extend_from_slice(&MAGIC);
+        out.push(flags);
+        if let Some(compression) = &self.compression {
+            out.extend_from_slice(&compression.dictionary_id.to_le_bytes());
+            out.extend_from_slice(&compression.original_len.to_le_bytes());
+        }
+        if let Some(nonce) = &self.nonce { out.extend_from_slice(nonce); }
+        if let Some(checksum) = self.checksum { out.extend_from_slice(&checksum.to_le_bytes()); }
+        if let Some(expires_at) = self.expires_at { out.extend_from_slice(&expires_at.to_le_bytes()); }
+        if let Some(type_tag) = &self.type_tag {
+            out.extend_from_slice(&(type_tag.len() as u16).to_le_bytes());
+            out.extend_from_slice(type_tag.as_bytes());
+        }
+    }
+
+    fn read(stored: &[u8]) -> Result<(Header, &[u8]), DataError> {
+        let mut reader = HeaderReader { rest: &stored[MAGIC.len()..] };
+        let flags = reader.u8()?;
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(DataError::DeserializationError(format!("unsupported value header flags {flags:#04x}")));
+        }
+
+        let mut header = Header::default();
+        if flags & COMPRESSED != 0 {
+            header.compression = Some(Compression { dictionary_id: reader.u32()?, original_len: reader.u32()? });
+        }
+        if flags & ENCRYPTED != 0 {
+            let mut nonce = [0; NONCE_LEN];
+            nonce.copy_from_slice(reader.take(NONCE_LEN)?);
+            header.nonce = Some(nonce);
+        }
+        if flags & CHECKSUMMED != 0 { header.checksum = Some(reader.u32()?); }
+        if flags & EXPIRES != 0 { header.expires_at = Some(reader.u64()?); }
+        if flags & TYPE_TAGGED != 0 {
+            let len = reader.u16()? as usize;
+            let Ok(type_tag) = String::from_utf8(reader.take(len)?.to_vec())
+                else { return Err(DataError::DeserializationError("type tag isn't valid UTF-8".to_string())) };
+            header.type_tag = Some(type_tag);
+        }
+        Ok((header, reader.rest))
+    }
+}
+
+struct HeaderReader<'a> {
+    rest: &'a [u8]
+}
+
+impl<'a> HeaderReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DataError> {
+        if self.rest.len() < len {
+            return Err(DataError::DeserializationError("truncated value header".to_string()));
+        }
+
+        let (taken, rest) = self.rest.split_at(len);
+        self.rest = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, DataError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, DataError> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    fn u32(&mut self) -> Result<u32, DataError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64, DataError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALUE: &[u8] = b"a value long enough that zstd has something to work with, with something to work with";
+
+    fn codec(encrypted: bool, checksummed: bool) -> ValueCodec {
+        ValueCodec::new(&PartitionConfig {
+            encryption_key: encrypted.then(|| EncryptionKey::Bytes(vec![1; KEY_LEN])),
+            checksum_on_write: checksummed,
+            checksum_on_read: checksummed,
+            ..PartitionConfig::default()
+        }).unwrap()
+    }
+
+    // Encodes VALUE with every header feature `flags` asks for
+    fn encode(codec: &ValueCodec, flags: u8) -> Vec<u8> {
+        let header = Header {
+            expires_at: (flags & EXPIRES != 0).then_some(1_700_000_000_000),
+            type_tag: (flags & TYPE_TAGGED != 0).then(|| "Token".to_string()),
+            ..Header::default()
+        };
+        let compress_with = (flags & COMPRESSED != 0).then_some(0);
+        codec.encode_with(VALUE, compress_with, header).unwrap()
+    }
+
+    #[test]
+    fn round_trips_every_flag_combination() {
+        for flags in 0..=KNOWN_FLAGS {
+            let codec = codec(flags & ENCRYPTED != 0, flags & CHECKSUMMED != 0);
+            let stored = encode(&codec, flags);
+            match flags {
+                0 => assert_eq!(stored, VALUE),
+                _ => assert_eq!(stored[..MAGIC.len() + 1], [MAGIC[0], MAGIC[1], MAGIC[2], flags], "flags {flags:#04x}")
+            }
+
+            assert_eq!(codec.decode(&stored).unwrap(), VALUE, "flags {flags:#04x}");
+            assert_eq!(codec.decode_owned(stored.clone()).unwrap(), VALUE, "flags {flags:#04x}");
+            let expiry = codec.expiry(&stored).unwrap();
+            assert_eq!(expiry.is_some(), flags & EXPIRES != 0, "flags {flags:#04x}");
+            let type_tag = codec.type_tag(&stored).unwrap();
+            assert_eq!(type_tag.as_deref(), (flags & TYPE_TAGGED != 0).then_some("Token"), "flags {flags:#04x}");
+        }
+    }
+
+    #[test]
+    fn round_trips_through_the_public_encoders() {
+        let codec = codec(false, false);
+        let expires_at = UNIX_EPOCH + Duration::from_millis(1_700_000_000_000);
+        let until = codec.encode_until(VALUE, Some(expires_at)).unwrap();
+        assert_eq!(codec.expiry(&until).unwrap(), Some(expires_at));
+
+        let tagged = codec.encode_tagged(VALUE, "Token").unwrap();
+        assert_eq!(codec.type_tag(&tagged).unwrap().as_deref(), Some("Token"));
+
+        let compressed = codec.encode_hinted(VALUE, CompressionHint::Zstd).unwrap();
+        assert!(compressed.len() < VALUE.len());
+        assert_eq!(codec.encode_hinted(VALUE, CompressionHint::None).unwrap(), VALUE);
+        for stored in [until, tagged, compressed] {
+            assert_eq!(codec.decode(&stored).unwrap(), VALUE);
+        }
+    }
+
+    #[test]
+    fn passes_legacy_values_through() {
+        let codec = codec(false, false);
+        let legacy = b"stored before headers existed".to_vec();
+        assert!(matches!(codec.decode(&legacy).unwrap(), Cow::Borrowed(_)));
+        assert_eq!(codec.expiry(&legacy).unwrap(), None);
+        assert_eq!(codec.type_tag(&legacy).unwrap(), None);
+
+        // a settings change doesn't stop header-less values from being read
+        assert_eq!(self::codec(true, true).decode(&legacy).unwrap(), legacy);
+    }
+
+    #[test]
+    fn gives_values_starting_with_magic_an_empty_header() {
+        let codec = codec(false, false);
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(b"not a header");
+        let stored = codec.encode(&data).unwrap();
+        assert_eq!(stored[..MAGIC.len() + 1], [MAGIC[0], MAGIC[1], MAGIC[2], 0]);
+        assert_eq!(codec.decode(&stored).unwrap(), data);
+    }
+
+    #[test]
+    fn rejects_truncated_headers() {
+        let codec = codec(true, true);
+        let stored = encode(&codec, KNOWN_FLAGS);
+        let header_len = MAGIC.len() + 1 + 8 + NONCE_LEN + 4 + 8 + 2 + "Token".len();
+        for len in MAGIC.len()..header_len {
+            let err = codec.decode(&stored[..len]).unwrap_err();
+            assert_eq!(err, DataError::DeserializationError("truncated value header".to_string()), "cut at {len}");
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_flags() {
+        let mut stored = MAGIC.to_vec();
+        stored.push(0b1000_0000);
+        assert!(matches!(codec(false, false).decode(&stored), Err(DataError::DeserializationError(_))));
+    }
+
+    #[test]
+    fn rejects_checksum_mismatch() {
+        let codec = codec(false, true);
+        let mut stored = encode(&codec, COMPRESSED);
+        let last = stored.len() - 1;
+        stored[last] ^= 0xFF;
+        assert_eq!(codec.decode(&stored).unwrap_err(), DataError::ChecksumMismatch);
+
+        // checksum_on_read off skips the check
+        let unchecked = ValueCodec::new(&PartitionConfig { checksum_on_write: true, ..PartitionConfig::default() }).unwrap();
+        let mut stored = unchecked.encode(VALUE).unwrap();
+        let last = stored.len() - 1;
+        stored[last] ^= 0xFF;
+        assert_eq!(unchecked.decode(&stored).unwrap().len(), VALUE.len());
+    }
+
+    #[test]
+    fn rejects_values_encrypted_under_another_key() {
+        let stored = encode(&codec(true, false), 0);
+        let other = ValueCodec::new(&PartitionConfig {
+            encryption_key: Some(EncryptionKey::Bytes(vec![2; KEY_LEN])),
+            ..PartitionConfig::default()
+        }).unwrap();
+        assert_eq!(other.decode(&stored).unwrap_err(), DataError::DecryptionFailed);
+        assert_eq!(codec(false, false).decode(&stored).unwrap_err(), DataError::DecryptionFailed);
+    }
+
+    #[test]
+    fn rejects_tampered_ciphertext() {
+        let codec = codec(true, false);
+        let mut stored = encode(&codec, 0);
+        let last = stored.len() - 1;
+        stored[last] ^= 0xFF;
+        assert_eq!(codec.decode(&stored).unwrap_err(), DataError::DecryptionFailed);
+    }
+}
@@ -0,0 +1,91 @@
+use std::fmt::{Display, Formatter};
+use std::sync::PoisonError;
+
+/// Errors returned by the data layer. These started out as pneumatic_core's DataError, but the
+/// provider has failure modes of its own, so the type now lives here (with the same variants).
+#[derive(Clone, Debug, PartialEq)]
+pub enum DataError {
+    FromStore(String),
+    DataNotFound,
+    Poisoned,
+    CacheError,
+    SerializationError(String),
+    DeserializationError(String),
+    /// The operation didn't complete before its deadline.
+    Timeout,
+    /// A value couldn't be authenticated with the partition's encryption key.
+    DecryptionFailed,
+    /// A value's checksum didn't match its contents.
+    ChecksumMismatch,
+    /// The partition doesn't accept writes.
+    ReadOnly,
+    /// The current RequestScope has used up its time budget.
+    BudgetExceeded,
+    /// A bulk load's input had these keys more than once.
+    DuplicateKeys(Vec<Vec<u8>>),
+    /// The disk holding the partition is full; see DataConfig::read_only_on_out_of_space.
+    OutOfSpace,
+    /// A save's read-back didn't match what was written; see DataConfig::verify_writes.
+    WriteVerificationFailed,
+    /// The key already has a value, which an append-only partition won't overwrite.
+    AlreadyExists,
+    /// The partition doesn't allow this operation, e.g. a delete in an append-only partition.
+    OperationNotAllowed,
+    /// A secondary is this many sequence numbers behind its primary, more than
+    /// PartitionConfig::max_replica_lag allows.
+    ReplicaBehind(u64),
+}
+
+impl Display for DataError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DataError::FromStore(msg) => write!(f, "store error: {msg}"),
+            DataError::DataNotFound => write!(f, "data not found"),
+            DataError::Poisoned => write!(f, "lock poisoned by a panicked thread"),
+            DataError::CacheError => write!(f, "cache error"),
+            DataError::SerializationError(msg) => write!(f, "serialization failed: {msg}"),
+            DataError::DeserializationError(msg) => write!(f, "deserialization failed: {msg}"),
+            DataError::Timeout => write!(f, "operation timed out"),
+            DataError::DecryptionFailed => write!(f, "value couldn't be decrypted with the partition's key"),
+            DataError::ChecksumMismatch => write!(f, "value doesn't match its checksum"),
+            DataError::ReadOnly => write!(f, "partition is read-only"),
+            DataError::BudgetExceeded => write!(f, "request exceeded its data layer time budget"),
+            DataError::DuplicateKeys(keys) => write!(f, "{} keys appear more than once in the input", keys.len()),
+            DataError::OutOfSpace => write!(f, "no space left on the partition's disk"),
+            DataError::WriteVerificationFailed => write!(f, "value read back after a save doesn't match what was written"),
+            DataError::AlreadyExists => write!(f, "key already has a value"),
+            DataError::OperationNotAllowed => write!(f, "operation not allowed on this partition"),
+            DataError::ReplicaBehind(lag) => write!(f, "secondary is {lag} sequence numbers behind its primary")
+        }
+    }
+}
+
+impl std::error::Error for DataError {}
+
+impl From<rocksdb::Error> for DataError {
+    fn from(err: rocksdb::Error) -> Self {
+        from_message(err.into_string())
+    }
+}
+
+impl From<std::io::Error> for DataError {
+    fn from(err: std::io::Error) -> Self {
+        from_message(err.to_string())
+    }
+}
+
+// RocksDB only reports running out of space as an IO error (or, once it has stopped writes
+// over one, a background error) whose message carries the OS's wording, so that's what this
+// goes by.
+fn from_message(msg: String) -> DataError {
+    match msg.contains("No space left on device") || msg.contains("NoSpace") {
+        true => DataError::OutOfSpace,
+        false => DataError::FromStore(msg)
+    }
+}
+
+impl<T> From<PoisonError<T>> for DataError {
+    fn from(_: PoisonError<T>) -> Self {
+        DataError::Poisoned
+    }
+}
@@ -0,0 +1,40 @@
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use crate::SafeDataProvider;
+
+struct Flusher {
+    stop: Sender<()>,
+    thread: JoinHandle<()>
+}
+
+static FLUSHER: Mutex<Option<Flusher>> = Mutex::new(None);
+
+/// Starts the background flusher if it isn't running.
+pub(crate) fn ensure_started(interval: Duration) {
+    let mut flusher = FLUSHER.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if flusher.is_some() { return; }
+
+    let (stop, stopped) = channel();
+    let thread = thread::spawn(move || loop {
+        // the last pass runs on the way out, so stopping never loses a period's writes
+        match stopped.recv_timeout(interval) {
+            Err(RecvTimeoutError::Timeout) => SafeDataProvider::sync_open_partitions(),
+            _ => {
+                SafeDataProvider::sync_open_partitions();
+                return;
+            }
+        }
+    });
+    *flusher = Some(Flusher { stop, thread });
+}
+
+/// Stops the flusher, returning once its final pass is done.
+pub(crate) fn stop() {
+    let flusher = FLUSHER.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take();
+    let Some(flusher) = flusher
+        else { return };
+    let _ = flusher.stop.send(());
+    let _ = flusher.thread.join();
+}
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock, RwLock};
+use pneumatic_core::tokens::Token;
+use crate::routing::Router;
+use crate::DataError;
+
+/// Called with the error, the partition it came from and the name of the failing operation.
+pub type ErrorHook = Arc<dyn Fn(&DataError, &str, &'static str) + Send + Sync>;
+
+/// Called with the key and the Token after it has been saved.
+pub type TokenSaveHook = Box<dyn Fn(&Vec<u8>, &Token) + Send + Sync>;
+
+/// Called with a Token about to be saved; an Err rejects the save with that error.
+pub type TokenValidator = Arc<dyn Fn(&Token) -> Result<(), DataError> + Send + Sync>;
+
+/// Resolves a data cache miss for a key, given the plain store read to fall back on.
+pub type DataLoader = Arc<dyn Fn(&[u8], &dyn Fn() -> Result<Vec<u8>, DataError>) -> Result<Vec<u8>, DataError> + Send + Sync>;
+
+/// Called with each entry's key and (decoded) value as compaction comes across it.
+pub type CompactionFilter = Arc<dyn Fn(&[u8], &[u8]) -> CompactionDecision + Send + Sync>;
+
+/// What compaction should do with an entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompactionDecision {
+    Keep,
+    Remove
+}
+
+static ERROR_HOOK: RwLock<Option<ErrorHook>> = RwLock::new(None);
+static TOKEN_SAVE_HOOKS: OnceLock<RwLock<HashMap<String, Vec<TokenSaveHook>>>> = OnceLock::new();
+static TOKEN_VALIDATORS: OnceLock<RwLock<HashMap<String, TokenValidator>>> = OnceLock::new();
+static COMPACTION_FILTERS: OnceLock<RwLock<HashMap<String, CompactionFilter>>> = OnceLock::new();
+static LOADERS: OnceLock<RwLock<HashMap<String, DataLoader>>> = OnceLock::new();
+static ROUTERS: OnceLock<RwLock<HashMap<String, Router>>> = OnceLock::new();
+
+pub(crate) fn set_error_hook(hook: ErrorHook) {
+    // a poisoned lock only means an earlier hook panicked, replacing it is still fine
+    let mut current = ERROR_HOOK.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    *current = Some(hook);
+}
+
+pub(crate) fn report_error(err: &DataError, partition_id: &str, op: &'static str) {
+    // cloned out so the hook can itself call set_error_hook without deadlocking
+    let hook = {
+        let current = ERROR_HOOK.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        current.clone()
+    };
+    if let Some(hook) = hook { hook(err, partition_id, op); }
+}
+
+pub(crate) fn add_token_save_hook(partition_id: &str, hook: TokenSaveHook) {
+    let mut hooks = token_save_hooks().write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    hooks.entry(partition_id.to_string()).or_default().push(hook);
+}
+
+pub(crate) fn run_token_save_hooks(key: &Vec<u8>, token: &Token, partition_id: &str) {
+    let hooks = token_save_hooks().read().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let Some(partition_hooks) = hooks.get(partition_id)
+        else { return };
+    for hook in partition_hooks { hook(key, token); }
+}
+
+pub(crate) fn set_token_validator(partition_id: &str, validator: TokenValidator) {
+    let mut validators = token_validators().write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    validators.insert(partition_id.to_string(), validator);
+}
+
+pub(crate) fn validate_token(token: &Token, partition_id: &str) -> Result<(), DataError> {
+    // cloned out so a slow validator doesn't hold up registering another one
+    let validator = {
+        let validators = token_validators().read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        validators.get(partition_id).cloned()
+    };
+    match validator {
+        None => Ok(()),
+        Some(validator) => validator(token)
+    }
+}
+
+pub(crate) fn set_compaction_filter(partition_id: &str, filter: CompactionFilter) {
+    let mut filters = compaction_filters().write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    filters.insert(partition_id.to_string(), filter);
+}
+
+pub(crate) fn compaction_filter(partition_id: &str) -> Option<CompactionFilter> {
+    let Ok(filters) = compaction_filters().read()
+        else { return None };
+    filters.get(partition_id).cloned()
+}
+
+pub(crate) fn set_router(partition_id: &str, router: Router) {
+    let mut routers = routers().write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    routers.insert(partition_id.to_string(), router);
+}
+
+pub(crate) fn router(partition_id: &str) -> Option<Router> {
+    let Ok(routers) = routers().read()
+        else { return None };
+    routers.get(partition_id).cloned()
+}
+
+pub(crate) fn set_loader(partition_id: &str, loader: DataLoader) {
+    let mut loaders = loaders().write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    loaders.insert(partition_id.to_string(), loader);
+}
+
+pub(crate) fn loader(partition_id: &str) -> Option<DataLoader> {
+    let Ok(loaders) = loaders().read()
+        else { return None };
+    loaders.get(partition_id).cloned()
+}
+
+fn loaders() -> &'static RwLock<HashMap<String, DataLoader>> {
+    LOADERS.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+fn routers() -> &'static RwLock<HashMap<String, Router>> {
+    ROUTERS.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+fn token_validators() -> &'static RwLock<HashMap<String, TokenValidator>> {
+    TOKEN_VALIDATORS.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+fn compaction_filters() -> &'static RwLock<HashMap<String, CompactionFilter>> {
+    COMPACTION_FILTERS.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+fn token_save_hooks() -> &'static RwLock<HashMap<String, Vec<TokenSaveHook>>> {
+    TOKEN_SAVE_HOOKS.get_or_init(|| RwLock::new(HashMap::new()))
+}
@@ -0,0 +1,58 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+
+// dead entries are only dropped from buckets that get looked up again, so every so often the
+// whole pool is swept
+const SWEEP_INTERVAL: u64 = 1024;
+
+/// Content-addressed pool of cached values, so that identical byte buffers cached under
+/// several keys share a single allocation.
+pub(crate) struct ValuePool {
+    entries: Mutex<PoolEntries>
+}
+
+#[derive(Default)]
+struct PoolEntries {
+    buckets: HashMap<u64, Vec<Weak<RwLock<Vec<u8>>>>>,
+    interned_since_sweep: u64
+}
+
+impl ValuePool {
+    pub(crate) fn new() -> Self {
+        ValuePool { entries: Mutex::new(PoolEntries::default()) }
+    }
+
+    pub(crate) fn intern(&self, data: Vec<u8>) -> Arc<RwLock<Vec<u8>>> {
+        let mut entries = self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        entries.interned_since_sweep += 1;
+        if entries.interned_since_sweep >= SWEEP_INTERVAL {
+            entries.buckets.retain(|_, bucket| {
+                bucket.retain(|value| value.strong_count() > 0);
+                !bucket.is_empty()
+            });
+            entries.interned_since_sweep = 0;
+        }
+
+        let bucket = entries.buckets.entry(hash_of(&data)).or_default();
+        bucket.retain(|value| value.strong_count() > 0);
+        for value in bucket.iter() {
+            let Some(shared) = value.upgrade()
+                else { continue };
+            let is_same = shared.read().map(|existing| *existing == data).unwrap_or(false);
+            if is_same { return shared; }
+        }
+
+        let shared = Arc::new(RwLock::new(data));
+        bucket.push(Arc::downgrade(&shared));
+        shared
+    }
+}
+
+fn hash_of(data: &Vec<u8>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish()
+}
@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock, RwLock};
+use std::time::Duration;
+use hdrhistogram::Histogram;
+
+// microseconds, to three significant figures; anything slower is recorded as the maximum
+const MAX_LATENCY_US: u64 = 60_000_000;
+const SIGNIFICANT_FIGURES: u8 = 3;
+
+/// The operations latency is tracked for.
+const TRACKED_OPS: [&str; 4] = ["get_token", "save_token", "get_data", "save_data"];
+
+/// Latency percentiles of one operation, in microseconds, all 0 if it hasn't run yet.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LatencyPercentiles {
+    pub count: u64,
+    pub p50_us: u64,
+    pub p95_us: u64,
+    pub p99_us: u64,
+}
+
+/// Latency distributions of a partition's core operations since start-up or the last
+/// reset_stats. Calls that failed are included.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LatencySnapshot {
+    pub get_token: LatencyPercentiles,
+    pub save_token: LatencyPercentiles,
+    pub get_data: LatencyPercentiles,
+    pub save_data: LatencyPercentiles,
+}
+
+type Histograms = RwLock<HashMap<(String, &'static str), Mutex<Histogram<u64>>>>;
+
+static HISTOGRAMS: OnceLock<Histograms> = OnceLock::new();
+
+pub(crate) fn is_tracked(op: &str) -> bool {
+    TRACKED_OPS.contains(&op)
+}
+
+pub(crate) fn record(partition_id: &str, op: &'static str, elapsed: Duration) {
+    let micros = elapsed.as_micros().min(MAX_LATENCY_US as u128) as u64;
+    let key = (partition_id.to_string(), op);
+
+    // callers only contend on the map the first time a partition runs an op
+    let histograms = histograms().read().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(histogram) = histograms.get(&key) {
+        histogram.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).saturating_record(micros);
+        return;
+    }
+    drop(histograms);
+
+    let mut histograms = histograms().write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let histogram = histograms.entry(key).or_insert_with(|| Mutex::new(new_histogram()));
+    histogram.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner()).saturating_record(micros);
+}
+
+pub(crate) fn snapshot(partition_id: &str) -> LatencySnapshot {
+    let histograms = histograms().read().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let percentiles = |op: &'static str| {
+        let Some(histogram) = histograms.get(&(partition_id.to_string(), op))
+            else { return LatencyPercentiles::default() };
+        let histogram = histogram.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        LatencyPercentiles {
+            count: histogram.len(),
+            p50_us: histogram.value_at_quantile(0.50),
+            p95_us: histogram.value_at_quantile(0.95),
+            p99_us: histogram.value_at_quantile(0.99),
+        }
+    };
+
+    LatencySnapshot {
+        get_token: percentiles("get_token"),
+        save_token: percentiles("save_token"),
+        get_data: percentiles("get_data"),
+        save_data: percentiles("save_data"),
+    }
+}
+
+pub(crate) fn reset() {
+    histograms().write().unwrap_or_else(|poisoned| poisoned.into_inner()).clear();
+}
+
+fn new_histogram() -> Histogram<u64> {
+    Histogram::new_with_max(MAX_LATENCY_US, SIGNIFICANT_FIGURES)
+        .expect("latency histogram bounds are valid")
+}
+
+fn histograms() -> &'static Histograms {
+    HISTOGRAMS.get_or_init(|| RwLock::new(HashMap::new()))
+}
@@ -0,0 +1,41 @@
+use std::sync::{Arc, OnceLock, RwLock};
+use pneumatic_core::encoding::deserialize_rmp_to;
+use pneumatic_core::tokens::Token;
+use crate::DataError;
+
+/// A Token cached as its serialized bytes and only deserialized the first time it's asked
+/// for, so a caller that just needs to know the Token exists never pays for decoding it (see
+/// PartitionConfig::lazy_tokens). Every caller sharing the entry shares the one decoded Token.
+pub struct LazyToken {
+    serialized: Vec<u8>,
+    token: OnceLock<Result<Arc<RwLock<Token>>, DataError>>
+}
+
+impl LazyToken {
+    pub(crate) fn new(serialized: Vec<u8>) -> Self {
+        LazyToken { serialized, token: OnceLock::new() }
+    }
+
+    /// Wraps a Token that's already deserialized, e.g. the one just saved.
+    pub(crate) fn loaded(token_ref: Arc<RwLock<Token>>) -> Self {
+        LazyToken { serialized: vec![], token: OnceLock::from(Ok(token_ref)) }
+    }
+
+    /// The Token, deserializing it on the first call. A Token that fails to deserialize
+    /// returns the same error every time.
+    pub fn get(&self) -> Result<Arc<RwLock<Token>>, DataError> {
+        self.token.get_or_init(|| match deserialize_rmp_to::<Token>(&self.serialized) {
+            Err(err) => Err(DataError::DeserializationError(err.to_string())),
+            Ok(token) => Ok(Arc::new(RwLock::new(token)))
+        }).clone()
+    }
+
+    pub(crate) fn serialized_len(&self) -> usize {
+        self.serialized.len()
+    }
+
+    /// Whether get has already deserialized the Token (or it was cached deserialized).
+    pub fn is_deserialized(&self) -> bool {
+        self.token.get().is_some()
+    }
+}
@@ -1,218 +1,2941 @@
-use std::sync::{Arc, OnceLock, RwLock};
-use std::time::Duration;
+mod backup;
+mod batch;
+mod canonical;
+mod changes;
+mod composite;
+mod config;
+mod contention;
+mod contracts;
+mod envelope;
+mod errors;
+mod flusher;
+mod hooks;
+mod lazy;
+mod locks;
+mod memory;
+mod merge;
+mod portable;
+mod pressure;
+mod routing;
+mod scan;
+mod scope;
+mod size;
+mod stats;
+mod tables;
+mod throttle;
+mod interning;
+mod latency;
+#[cfg(feature = "async")]
+mod deadline;
+
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, RwLock, RwLockReadGuard, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use std::io::{Read, Write};
 use std::ops::Deref;
+use moka::notification::RemovalCause;
+use moka::policy::EvictionPolicy;
 use moka::sync::Cache;
+use rayon::prelude::*;
 use pneumatic_core::data::*;
 use pneumatic_core::tokens::*;
 use pneumatic_core::encoding::*;
-use rocksdb::{DBWithThreadMode, MultiThreaded, Options};
+use rocksdb::checkpoint::Checkpoint;
+use rocksdb::compaction_filter::Decision;
+use rocksdb::statistics::Ticker;
+use rocksdb::{BlockBasedOptions, BottommostLevelCompaction, BoundColumnFamily, CompactOptions, DBWithThreadMode, Direction, IngestExternalFileOptions, IteratorMode, MultiThreaded,
+              Options, ReadOptions, SstFileWriter, WriteBatch, WriteOptions};
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+
+pub use backup::BackupProgress;
+pub use batch::{BatchPreview, DataBatch};
+pub use canonical::serialize_canonical;
+pub use changes::ChangeRecord;
+use changes::BatchRecords;
+pub use composite::{composite_key, namespace_prefix, split_composite_key};
+pub use config::{DataConfig, EffectiveConfig, EncryptionKey, PartitionConfig, ZstdDictionary};
+pub use contracts::ContractStore;
+pub use errors::DataError;
+pub use hooks::{CompactionDecision, CompactionFilter, DataLoader, ErrorHook, TokenSaveHook, TokenValidator};
+pub use latency::{LatencyPercentiles, LatencySnapshot};
+pub use lazy::LazyToken;
+pub use envelope::CompressionHint;
+pub use portable::PortableManifest;
+use envelope::ValueCodec;
+use interning::ValuePool;
+pub use routing::{BackendId, Router};
+use routing::RoutedDb;
+pub use scan::{MergeEntry, MergeIter, ScanErrorPolicy, ScanStream, TokenScan};
+pub use scope::RequestScope;
+pub use size::{SizeCallback, SizeLevel, SizeThresholds};
+pub use stats::StatsSnapshot;
+pub use tables::Table;
+use stats::Stat;
+
+pub struct SafeDataProvider { }
+
+//impl DataProvider for SafeDataProvider {}
+
+impl SafeDataProvider {
+    /// Sets the provider-wide config. Must be called before the first data access, otherwise
+    /// the defaults are locked in and the rejected config is handed back.
+    pub fn configure(config: DataConfig) -> Result<(), DataConfig> {
+        CONFIG.set(config)
+    }
+
+    /// The settings `partition_id` runs with, resolved from the provider-wide config and the
+    /// partition's overrides. Reflects the config in effect (the defaults, if configure was
+    /// never called or came too late) and the hooks registered so far, which a partition that's
+    /// already open may have been opened without.
+    pub fn effective_config(partition_id: &str) -> EffectiveConfig {
+        Self::get_config().effective(partition_id, CACHE_TIME_TO_IDLE)
+    }
+
+    /// Registers a hook that sees every error returned by a provider operation. The hook is
+    /// purely observational and replaces any previously registered one. An operation the
+    /// provider calls while running another (e.g. from a loader) fails into the outer one, so
+    /// only the outer operation's error is reported.
+    pub fn set_error_hook<F>(hook: F)
+        where F: Fn(&DataError, &str, &'static str) + Send + Sync + 'static {
+        hooks::set_error_hook(Arc::new(hook))
+    }
+
+    /// Registers a hook run after every successful save_token in `partition_id`, e.g. to keep
+    /// a derived aggregate up to date. Hooks run in registration order on the saving thread,
+    /// after the Token is written and cached, while a read lock on it is held (so a hook must
+    /// not save that same Token). They are not atomic with the write: a crash can land between
+    /// the two, and hooks for concurrent saves may interleave, so derived state should be
+    /// recomputable from the Tokens themselves.
+    pub fn add_token_save_hook<F>(partition_id: &str, hook: F)
+        where F: Fn(&Vec<u8>, &Token) + Send + Sync + 'static {
+        hooks::add_token_save_hook(partition_id, Box::new(hook))
+    }
+
+    /// Registers a check every save_token and save_token_bytes in `partition_id` runs the Token
+    /// through before writing it, e.g. to enforce a non-negative balance in one place. An Err
+    /// rejects the save with that error and nothing is written or cached. It runs on the saving
+    /// thread before the key's lock is taken, holding only a read lock on the Token, so it can
+    /// read other keys but shouldn't be slow; a Token changed by another thread between the
+    /// check and the write is written as changed. Registering again replaces the validator.
+    pub fn set_validator<F>(partition_id: &str, validator: F)
+        where F: Fn(&Token) -> Result<(), DataError> + Send + Sync + 'static {
+        hooks::set_token_validator(partition_id, Arc::new(validator))
+    }
+
+    /// Registers the function `partition_id`'s data cache misses are resolved with, in place
+    /// of reading the store, e.g. to fetch from a peer. It's called with the key and a
+    /// function doing the usual store read, to fall back on (or build on); what it returns is
+    /// cached and handed to the caller, an Err fails the read. Nothing it returns is written to
+    /// the store. It runs without any of the provider's locks held, so it can read or save any
+    /// key through the provider, but concurrent misses for a key each make their own call; if
+    /// something else caches the key while the loader runs (e.g. a save), that value is what
+    /// the read returns and the loaded one is dropped. Covers get_data, get_data_shared and
+    /// typed reads, not Tokens or get_data_no_cache. Registering again replaces the loader.
+    pub fn set_loader<F>(partition_id: &str, loader: F)
+        where F: Fn(&[u8], &dyn Fn() -> Result<Vec<u8>, DataError>) -> Result<Vec<u8>, DataError> + Send + Sync + 'static {
+        hooks::set_loader(partition_id, Arc::new(loader))
+    }
+
+    /// Registers a filter RocksDB runs over `partition_id`'s entries (column families
+    /// included) during compaction, e.g. to drop expired entries or tombstones without a
+    /// separate sweep. It runs on RocksDB's background threads, sees values decoded, and only
+    /// keeps or removes entries; an entry whose value can't be decoded is kept. It's installed
+    /// when the partition is opened, so register it before first use. Removed entries can still
+    /// be read until compaction actually reaches them.
+    pub fn set_compaction_filter<F>(partition_id: &str, filter: F)
+        where F: Fn(&[u8], &[u8]) -> CompactionDecision + Send + Sync + 'static {
+        hooks::set_compaction_filter(partition_id, Arc::new(filter))
+    }
+
+    /// Splits `partition_id` over several backends, with `router` picking the one each key is
+    /// read from and written to (see [BackendId]), e.g. to keep hot, disposable keys in memory
+    /// and the rest in RocksDB. Scans merge the backends into one ordered keyspace; batches and
+    /// bulk loads spanning both backends are only atomic per backend. Writes to memory fail
+    /// whenever RocksDB's would (read-only, out of space, append-only), and the provider's own
+    /// bookkeeping keys (counters, rings, blobs, refcounts) always stay in RocksDB, whatever
+    /// the router says. The router is installed when the partition is opened, so register it
+    /// before first use.
+    pub fn set_router<F>(partition_id: &str, router: F)
+        where F: Fn(&[u8]) -> BackendId + Send + Sync + 'static {
+        hooks::set_router(partition_id, Arc::new(router))
+    }
+
+    pub fn get_token(key: &Vec<u8>, partition_id: &str) -> Result<Arc<RwLock<Token>>, DataError> {
+        Self::observe("get_token", partition_id, || {
+            Self::check_replica_lag(partition_id)?;
+            Self::load_token(key, partition_id)
+        })
+    }
+
+    pub fn save_token(key: &Vec<u8>, token_ref: Arc<RwLock<Token>>, partition_id: &str)
+                      -> Result<(), DataError> {
+        Self::observe("save_token", partition_id, || {
+            {
+                let Ok(token) = token_ref.read()
+                    else { return Err(DataError::Poisoned) };
+                hooks::validate_token(&token, partition_id)?;
+            }
+
+            let db = Self::get_db(partition_id)?;
+            let guard = locks::lock_key(key, partition_id);
+            let _ = db.save_token(key, &token_ref)?;
+            Self::cache_written_token(key, partition_id, token_ref.clone());
+            // hooks may save other keys, which could share this lock's stripe
+            drop(guard);
+
+            if let Ok(token) = token_ref.read() { hooks::run_token_save_hooks(key, &token, partition_id); }
+            Ok(())
+        })
+    }
+
+    /// The Token under `key` as it's serialized in the store, bypassing the cache. With
+    /// `canonical_tokens` on these are canonical bytes (see [serialize_canonical]) for every
+    /// Token saved through save_token.
+    pub fn get_token_raw(key: &Vec<u8>, partition_id: &str) -> Result<Vec<u8>, DataError> {
+        Self::observe("get_token_raw", partition_id, || {
+            Self::check_replica_lag(partition_id)?;
+            Self::get_db(partition_id)?.get_data(key)
+        })
+    }
+
+    /// Saves an already serialized Token as-is, e.g. canonical bytes received from another
+    /// node, so the stored bytes are exactly `serialized` rather than a re-serialization. The
+    /// bytes are deserialized once, to validate them and to cache the Token, and save hooks
+    /// run as for save_token.
+    pub fn save_token_bytes(key: &Vec<u8>, serialized: Vec<u8>, partition_id: &str) -> Result<(), DataError> {
+        Self::observe("save_token", partition_id, || {
+            let token = match deserialize_rmp_to::<Token>(&serialized) {
+                Err(err) => return Err(DataError::DeserializationError(err.to_string())),
+                Ok(token) => token
+            };
+            hooks::validate_token(&token, partition_id)?;
+
+            let db = Self::get_db(partition_id)?;
+            let token_ref = Arc::new(RwLock::new(token));
+            let guard = locks::lock_key(key, partition_id);
+            let _ = db.save_data(key, &serialized)?;
+            Self::cache_written_token(key, partition_id, token_ref.clone());
+            drop(guard);
+
+            if let Ok(token) = token_ref.read() { hooks::run_token_save_hooks(key, &token, partition_id); }
+            Ok(())
+        })
+    }
+
+    /// Loads the Tokens under `keys` and calls `f` with all of them read-locked at once (in
+    /// the order of `keys`, a repeated key only appearing at its first position), so no write
+    /// through the cache can land on one of them midway through reading the set. Locks are
+    /// taken in key order, so concurrent multi-key locking in the same order can't deadlock.
+    /// A save_token that replaces an entry isn't blocked, though: `f` sees the Tokens as they
+    /// were cached when loaded.
+    pub fn with_tokens<R>(keys: &[Vec<u8>], partition_id: &str, f: impl FnOnce(&[RwLockReadGuard<'_, Token>]) -> R)
+                          -> Result<R, DataError> {
+        let mut unique_keys: Vec<&Vec<u8>> = vec![];
+        for key in keys {
+            if !unique_keys.contains(&key) { unique_keys.push(key); }
+        }
+        let tokens = unique_keys.iter()
+            .map(|key| Self::get_token(key, partition_id))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Self::observe("with_tokens", partition_id, || {
+            let mut lock_order: Vec<usize> = (0..unique_keys.len()).collect();
+            lock_order.sort_by_key(|&i| unique_keys[i]);
+
+            let mut guards: Vec<Option<RwLockReadGuard<'_, Token>>> = tokens.iter().map(|_| None).collect();
+            for i in lock_order {
+                let Ok(guard) = contention::read(&tokens[i], unique_keys[i], partition_id)
+                    else { return Err(DataError::Poisoned) };
+                guards[i] = Some(guard);
+            }
+
+            let guards: Vec<RwLockReadGuard<'_, Token>> = guards.into_iter().flatten().collect();
+            Ok(f(&guards))
+        })
+    }
+
+    /// The Token under `key`, left serialized until [LazyToken::get] is first called. In a
+    /// partition with `lazy_tokens` on that's exactly how it's cached, so a hit or a miss only
+    /// costs the read, and checking a Token exists never deserializes it; elsewhere this is
+    /// get_token with the Token already deserialized. DataNotFound if there's no such Token.
+    pub fn get_token_lazy(key: &Vec<u8>, partition_id: &str) -> Result<Arc<LazyToken>, DataError> {
+        Self::observe("get_token", partition_id, || {
+            Self::check_replica_lag(partition_id)?;
+            match Self::has_lazy_tokens(partition_id) {
+                true => Self::load_lazy_token(key, partition_id),
+                false => Ok(Arc::new(LazyToken::loaded(Self::load_token(key, partition_id)?)))
+            }
+        })
+    }
+
+    /// Re-reads the Token from disk and replaces its cache entry, or evicts the entry if the
+    /// Token no longer exists. Callers already holding the old entry keep seeing the old value.
+    pub fn refresh_token(key: &Vec<u8>, partition_id: &str) -> Result<(), DataError> {
+        Self::observe("refresh_token", partition_id, || {
+            let _guard = locks::lock_key(key, partition_id);
+            match Self::get_token_from_db(key, partition_id) {
+                Err(DataError::DataNotFound) => {
+                    Self::invalidate_token_entry(&cache_key(key, partition_id));
+                    Ok(())
+                },
+                Err(err) => Err(err),
+                Ok(token) => {
+                    Self::put_in_token_cache(key, partition_id, Arc::new(RwLock::new(token)));
+                    Ok(())
+                }
+            }
+        })
+    }
+
+    /// Drops each key's token and data cache entries for `partition_id`, e.g. after the keys
+    /// were changed behind the provider's back. Nothing is read from or written to disk.
+    pub fn invalidate_many(keys: &[Vec<u8>], partition_id: &str) {
+        for key in keys {
+            let cache_key = cache_key(key, partition_id);
+            Self::invalidate_token_entry(&cache_key);
+            Self::invalidate_data_entry(&cache_key);
+        }
+    }
+
+    /// Has the token and data caches give memory back while `signal` reports pressure, a value
+    /// from 0.0 (none) to 1.0 (critical) polled every `poll_every`. Under pressure p each cache
+    /// is trimmed to (1 - p) of `cache_capacity` (ignored under a cache_memory_budget_mb), or,
+    /// if it's unbounded, of its size when pressure rose above 0. Polls at a steady pressure
+    /// hold a cache at that target rather than shrinking it further, and caches grow back
+    /// normally once pressure drops to 0.
+    /// Best-effort: trimming only happens on polls, removes arbitrary entries rather than the
+    /// least recently used ones, and can't help with memory held by callers' own Arcs. Calling
+    /// it again replaces the signal.
+    pub fn set_memory_pressure_signal<F>(signal: F, poll_every: Duration)
+        where F: Fn() -> f64 + Send + Sync + 'static {
+        pressure::set_signal(Arc::new(signal), poll_every)
+    }
+
+    /// Keys of `partition_id` that currently have a token or data cache entry, in key order.
+    /// A best-effort, point-in-time view: entries can be inserted or evicted while it's taken,
+    /// and expired entries not yet cleaned up may still show.
+    pub fn cached_keys(partition_id: &str) -> Vec<Vec<u8>> {
+        let token_keys = Self::get_token_cache().iter().map(|(cache_key, _)| cache_key);
+        let lazy_keys = Self::get_lazy_token_cache().iter().map(|(cache_key, _)| cache_key);
+        let data_keys = Self::get_data_cache().iter().map(|(cache_key, _)| cache_key);
+        let shared_keys = Self::get_shared_data_cache().iter().map(|(cache_key, _)| cache_key);
+        let mut keys: Vec<Vec<u8>> = token_keys.chain(lazy_keys).chain(data_keys).chain(shared_keys)
+            .filter(|cache_key| cache_key.0 == partition_id)
+            .map(|cache_key| cache_key.1.clone())
+            .collect();
+        keys.sort_unstable();
+        keys.dedup();
+        keys
+    }
+
+    pub fn get_data(key: &Vec<u8>, partition_id: &str)
+                    -> Result<Arc<RwLock<Vec<u8>>>, DataError> {
+        Self::observe("get_data", partition_id, || {
+            Self::check_replica_lag(partition_id)?;
+            Self::load_data(key, partition_id)
+        })
+    }
+
+    /// Like get_data, but hands out the value itself rather than a lock around it. With
+    /// `immutable_data_cache` on this is the cheapest way to read data: no lock is taken, and
+    /// the Arc is the one the cache holds. With it off the value is copied out of its cached
+    /// lock on every call.
+    pub fn get_data_shared(key: &Vec<u8>, partition_id: &str) -> Result<Arc<Vec<u8>>, DataError> {
+        Self::observe("get_data", partition_id, || {
+            Self::check_replica_lag(partition_id)?;
+            Self::load_shared(key, partition_id)
+        })
+    }
+
+    /// Same as get_data, but a miss doesn't populate the data cache, so e.g. analytical reads
+    /// over lots of cold keys don't push out the hot working set. A value that's already
+    /// cached is still returned from the cache. On a miss the returned entry is the caller's
+    /// own: writing through it changes nothing else.
+    pub fn get_data_no_cache(key: &Vec<u8>, partition_id: &str) -> Result<Arc<RwLock<Vec<u8>>>, DataError> {
+        Self::observe("get_data", partition_id, || {
+            Self::check_replica_lag(partition_id)?;
+            let cache_key = cache_key(key, partition_id);
+            if let Some(data_entry) = Self::get_data_cache().get(&cache_key) {
+                stats::record(Stat::DataCacheHit);
+                return Ok(data_entry);
+            }
+            if let Some(shared) = Self::get_shared_data_cache().get(&cache_key) {
+                stats::record(Stat::DataCacheHit);
+                return Ok(Arc::new(RwLock::new(shared.to_vec())));
+            }
+
+            stats::record(Stat::DataCacheMiss);
+            let data = Self::get_db(partition_id)?.get_data(key)?;
+            Ok(Arc::new(RwLock::new(data)))
+        })
+    }
+
+    /// Reads the value under `key` (through the data cache) and deserializes it into a `T`.
+    pub fn get_typed_data<T: DeserializeOwned>(key: &Vec<u8>, partition_id: &str) -> Result<T, DataError> {
+        Self::observe("get_typed_data", partition_id, || {
+            Self::check_replica_lag(partition_id)?;
+            Self::load_typed_data(key, partition_id)
+        })
+    }
+
+    /// Like get_typed_data, but a missing key yields `T::default()` instead of DataNotFound,
+    /// for records like settings that have a sensible value before they're first saved.
+    pub fn get_typed_or_default<T: DeserializeOwned + Default>(key: &Vec<u8>, partition_id: &str)
+                                                              -> Result<T, DataError> {
+        Self::observe("get_typed_or_default", partition_id, || {
+            Self::check_replica_lag(partition_id)?;
+            match Self::load_typed_data(key, partition_id) {
+                Err(DataError::DataNotFound) => Ok(T::default()),
+                result => result
+            }
+        })
+    }
+
+    pub fn save_data(key: &Vec<u8>, data: Vec<u8>, partition_id: &str) -> Result<(), DataError> {
+        Self::observe("save_data", partition_id, || {
+            let db = Self::get_db(partition_id)?;
+            let _guard = locks::lock_key(key, partition_id);
+            let _ = db.save_data(key, &data)?;
+            Self::cache_written_data(key, partition_id, data);
+            Ok(())
+        })
+    }
+
+    /// Same as save_data for callers that still need `data` afterwards: the bytes are only
+    /// copied once, into the cache entry.
+    pub fn save_data_ref(key: &Vec<u8>, data: &[u8], partition_id: &str) -> Result<(), DataError> {
+        Self::observe("save_data", partition_id, || {
+            let db = Self::get_db(partition_id)?;
+            let _guard = locks::lock_key(key, partition_id);
+            let _ = db.save_data(key, data)?;
+            Self::cache_written_data(key, partition_id, data.to_vec());
+            Ok(())
+        })
+    }
+
+    /// Swaps the values under `key_a` and `key_b` in one atomic write, updating both keys'
+    /// cache entries. DataNotFound, with nothing changed, if either key is missing (or
+    /// expired). Values move exactly as stored, so each keeps its expiry, type tag and
+    /// compression; in a routed partition both keys should route to the same backend.
+    pub fn swap_keys(key_a: &Vec<u8>, key_b: &Vec<u8>, partition_id: &str) -> Result<(), DataError> {
+        Self::observe("swap_keys", partition_id, || {
+            let db = Self::get_db(partition_id)?;
+            // both locks are held from the reads to the cache updates, so no save can slip in
+            let _guards = locks::lock_keys([key_a, key_b].into_iter(), partition_id);
+            let (value_a, expires_a) = db.get_data_with_expiry(key_a)?;
+            let (value_b, expires_b) = db.get_data_with_expiry(key_b)?;
+            if key_a == key_b { return Ok(()); }
+
+            // encryption only authenticates values with the partition's key, not their own
+            // key, so stored bytes stay readable under the other key
+            let stored_a = db.get_stored(key_a)?;
+            let stored_b = db.get_stored(key_b)?;
+            db.put_stored(&[(key_a.clone(), stored_b), (key_b.clone(), stored_a)])?;
+            for (key, value, expires_at) in [(key_a, value_b, expires_b), (key_b, value_a, expires_a)] {
+                Self::invalidate_cache_entry(key, partition_id);
+                // expiring values aren't cached, as on reads
+                if expires_at.is_none() { Self::cache_written_data(key, partition_id, value); }
+            }
+            Ok(())
+        })
+    }
+
+    /// Stores `data` once under its SHA-256 hash, however many times it's saved, and returns
+    /// the hash. Every save takes a reference to the blob, counted in the partition through
+    /// the merge operator, which release_content gives back; the blob is only deleted once the
+    /// last reference is released. Blobs and refcounts live under reserved keys (starting with
+    /// a 0 byte, which full scans will show) and bypass the caches.
+    pub fn save_content_addressed(data: &[u8], partition_id: &str) -> Result<Vec<u8>, DataError> {
+        Self::observe("save_content_addressed", partition_id, || {
+            let hash = Sha256::digest(data).to_vec();
+            let db = Self::get_db(partition_id)?;
+            let refcount_key = merge::refcount_key(&hash);
+            let _guard = locks::lock_key(&refcount_key, partition_id);
+
+            // the blob goes in first: a crash before the refcount leaves an unreferenced blob,
+            // which the next save of the same content picks up, rather than a dangling count
+            let blob_key = merge::blob_key(&hash);
+            if db.value_size(&blob_key)?.is_none() { db.save_data(&blob_key, data)?; }
+            db.adjust_refcount(&refcount_key, 1)?;
+            Ok(hash)
+        })
+    }
+
+    /// The blob stored under `hash` by save_content_addressed.
+    pub fn get_content(hash: &[u8], partition_id: &str) -> Result<Vec<u8>, DataError> {
+        Self::observe("get_content", partition_id, || Self::get_db(partition_id)?.get_data(&merge::blob_key(hash)))
+    }
+
+    /// Gives back one reference to the blob under `hash`, deleting it if that was the last one,
+    /// and returns how many references are left. DataNotFound if the blob has none.
+    pub fn release_content(hash: &[u8], partition_id: &str) -> Result<u64, DataError> {
+        Self::observe("release_content", partition_id, || {
+            let db = Self::get_db(partition_id)?;
+            let refcount_key = merge::refcount_key(hash);
+            let _guard = locks::lock_key(&refcount_key, partition_id);
+
+            // a reference released to below zero means there were none, which is undone here
+            let remaining = db.adjust_refcount(&refcount_key, -1)?;
+            if remaining > 0 { return Ok(remaining as u64); }
+
+            db.write_batch(&[BatchOp::Delete(merge::blob_key(hash)), BatchOp::Delete(refcount_key)])?;
+            match remaining {
+                0 => Ok(0),
+                _ => Err(DataError::DataNotFound)
+            }
+        })
+    }
+
+    /// Hands out the next id in `namespace`: 1 first, then 2, and so on, each to exactly one
+    /// caller however many call concurrently. Ids come from a counter kept in the partition
+    /// (under a reserved key starting with a 0 byte, which full scans will show), incremented
+    /// with a merge and synced to disk before the id is returned, so none is handed out twice
+    /// even across crashes. An id whose caller crashes before using it is lost, though, so
+    /// the ids actually used can have gaps. The counter only changes through next_id: saving
+    /// over its key any other way (save_data, a batch, bulk_load) fails with
+    /// OperationNotAllowed, as does saving over a content refcount.
+    pub fn next_id(namespace: &str, partition_id: &str) -> Result<u64, DataError> {
+        Self::observe("next_id", partition_id, || {
+            let db = Self::get_db(partition_id)?;
+            let key = merge::counter_key(namespace);
+            let _guard = locks::lock_key(&key, partition_id);
+            db.increment(&key)
+        })
+    }
+
+    /// Writes `data` as the newest value of the ring under `base_key`, which keeps the last
+    /// `ring_size` values written (a ring_size of 0 is taken as 1), overwriting the oldest once
+    /// full. The slot and the ring's head are written in one batch under the ring's lock, so
+    /// concurrent writers each get their own slot and readers never see a half-done write.
+    /// Ring entries live under reserved keys (starting with a 0 byte, which full scans will
+    /// show), apart from `base_key` itself, and bypass the caches. Writing with a different
+    /// ring_size than before empties the ring first.
+    pub fn save_data_ring(base_key: &Vec<u8>, data: Vec<u8>, ring_size: u32, partition_id: &str)
+                          -> Result<(), DataError> {
+        Self::observe("save_data_ring", partition_id, || {
+            let ring_size = ring_size.max(1);
+            let db = Self::get_db(partition_id)?;
+            let head_key = merge::ring_head_key(base_key);
+            let _guard = locks::lock_key(&head_key, partition_id);
+
+            let mut ops = vec![];
+            let written = match Self::ring_head(db.as_ref(), &head_key)? {
+                Some((written, size)) if size == ring_size => written,
+                Some((_, size)) => {
+                    ops.extend((0..size as u64).map(|slot| BatchOp::Delete(merge::ring_slot_key(base_key, slot))));
+                    0
+                },
+                None => 0
+            };
+            ops.push(BatchOp::Put(merge::ring_slot_key(base_key, written % ring_size as u64), data));
+            ops.push(BatchOp::Put(head_key, merge::ring_head_value(written + 1, ring_size)));
+            db.write_batch(&ops)
+        })
+    }
+
+    /// The values in the ring under `base_key` (see save_data_ring), newest first. Empty if
+    /// nothing has been written to it.
+    pub fn get_ring(base_key: &Vec<u8>, partition_id: &str) -> Result<Vec<Vec<u8>>, DataError> {
+        Self::observe("get_ring", partition_id, || {
+            let db = Self::get_db(partition_id)?;
+            let head_key = merge::ring_head_key(base_key);
+            let _guard = locks::lock_key(&head_key, partition_id);
+
+            let Some((written, ring_size)) = Self::ring_head(db.as_ref(), &head_key)?
+                else { return Ok(vec![]) };
+            (1..=written.min(ring_size as u64))
+                .map(|age| db.get_data(&merge::ring_slot_key(base_key, (written - age) % ring_size as u64)))
+                .collect()
+        })
+    }
+
+    /// Like save_data, but compresses (or doesn't) as `hint` says instead of following the
+    /// partition's config, e.g. to skip recompressing media. How the value was compressed is
+    /// recorded in its header, so reads don't need to know. Appending to the value re-encodes
+    /// it as the partition's config says.
+    pub fn save_data_with_compression(key: &Vec<u8>, data: Vec<u8>, hint: CompressionHint, partition_id: &str)
+                                      -> Result<(), DataError> {
+        Self::observe("save_data", partition_id, || {
+            let db = Self::get_db(partition_id)?;
+            let _guard = locks::lock_key(key, partition_id);
+            db.save_data_hinted(key, &data, hint)?;
+            Self::cache_written_data(key, partition_id, data);
+            Ok(())
+        })
+    }
+
+    /// Saves `data` to be read as absent from `expire_at` on, e.g. for a lease ending at a
+    /// known time. The expiry goes in the value's header, and an expired value is skipped by
+    /// scans and deleted by the first cached read (get_data and friends) that finds it; one
+    /// that's never read again stays on disk until overwritten or deleted. Appends keep the
+    /// expiry; saving the key any other way removes it. Expiring values bypass the data cache,
+    /// so every read of them goes to the store. Only RocksDB-backed keys support this.
+    ///
+    /// Expiry is checked against the clock of whichever node reads the value, so with
+    /// replicated or copied data a value can expire earlier or later than `expire_at` by the
+    /// skew between the writer's and the reader's clocks, and a clock stepped backwards brings
+    /// expired values back until they're deleted.
+    pub fn save_data_until(key: &Vec<u8>, data: &[u8], expire_at: SystemTime, partition_id: &str)
+                           -> Result<(), DataError> {
+        Self::observe("save_data_until", partition_id, || {
+            let db = Self::get_db(partition_id)?;
+            let _guard = locks::lock_key(key, partition_id);
+            db.save_data_until(key, data, expire_at)?;
+            Self::invalidate_cache_entry(key, partition_id);
+            Ok(())
+        })
+    }
+
+    /// Appends `suffix` to the value under `key` (creating it if there's none) without reading
+    /// the value first: RocksDB records the append and concatenates when the value is next
+    /// read or compacted, so concurrent appends all land, in some order. The key's cache
+    /// entry is invalidated, since the full value isn't known here.
+    pub fn append_to_data(key: &Vec<u8>, suffix: &[u8], partition_id: &str) -> Result<(), DataError> {
+        Self::observe("append_to_data", partition_id, || {
+            let db = Self::get_db(partition_id)?;
+            let _guard = locks::lock_key(key, partition_id);
+            db.merge(key, suffix)?;
+            Self::invalidate_cache_entry(key, partition_id);
+            Ok(())
+        })
+    }
+
+    /// Same as save_data, but leaves the data cache alone, so values that won't be read again
+    /// soon don't displace hotter entries. Any entry already cached for `key` is invalidated
+    /// rather than left stale.
+    pub fn save_data_no_cache(key: &Vec<u8>, data: &[u8], partition_id: &str) -> Result<(), DataError> {
+        Self::observe("save_data", partition_id, || {
+            let db = Self::get_db(partition_id)?;
+            let _guard = locks::lock_key(key, partition_id);
+            let _ = db.save_data(key, data)?;
+            Self::invalidate_cache_entry(key, partition_id);
+            Ok(())
+        })
+    }
+
+    /// Copies every key of `src_partition_id` into `dest_partition_id`, re-reading each written
+    /// value from `dest_partition_id` to verify it. Keys that don't read back byte-for-byte
+    /// are reported as mismatches rather than failing the whole copy. Values are copied exactly
+    /// as stored, headers included, so expiry times, type tags and id counters carry over, but
+    /// `dest_partition_id` needs the same encryption key and dictionaries to read them (and,
+    /// if routed, to route keys to the same backends). Expired values not yet deleted are
+    /// copied too, and stay expired.
+    pub fn copy_partition(src_partition_id: &str, dest_partition_id: &str)
+                          -> Result<CopyReport, DataError> {
+        Self::observe("copy_partition", src_partition_id, || {
+            let src = Self::get_db(src_partition_id)?;
+            let dest = Self::get_db(dest_partition_id)?;
+
+            let mut report = CopyReport::default();
+            let mut write_result = Ok(());
+            src.scan_stored(None, None, &mut |key, value| {
+                let key = key.to_vec();
+                let _guard = locks::lock_key(&key, dest_partition_id);
+                if let Err(err) = dest.put_stored(&[(key.clone(), value.to_vec())]) {
+                    write_result = Err(err);
+                    return false;
+                }
+
+                Self::invalidate_cache_entry(&key, dest_partition_id);
+                match dest.get_stored(&key) {
+                    Ok(copied) if copied == value => report.keys_copied += 1,
+                    _ => report.mismatches.push(key)
+                }
+                true
+            })?;
+
+            write_result.map(|_| report)
+        })
+    }
+
+    /// Streams every entry with `start <= key < end`, in key order, from a snapshot taken
+    /// when the stream is created (see [ScanStream]).
+    pub fn scan_range(start: &Vec<u8>, end: &Vec<u8>, partition_id: &str)
+                      -> Result<ScanStream<(Vec<u8>, Vec<u8>)>, DataError> {
+        Self::observe("scan_range", partition_id, || {
+            Self::stream_range("scan_range", Some(start.clone()), Some(end.clone()), partition_id)
+        })
+    }
+
+    /// Walks partitions `a` and `b` together in key order, yielding keys found in both with
+    /// both values and the rest with the side they're in, e.g. for a streaming join over
+    /// partitions sharing a keyspace. Each side reads from its own snapshot, taken when this
+    /// is called (see [ScanStream]), so the two aren't from the same instant.
+    pub fn merge_iter(a: &str, b: &str) -> Result<MergeIter, DataError> {
+        let a = Self::observe("merge_iter", a, || Self::stream_range("merge_iter", None, None, a))?;
+        let b = Self::observe("merge_iter", b, || Self::stream_range("merge_iter", None, None, b))?;
+        Ok(MergeIter::new(a, b))
+    }
+
+    /// Like [scan_range](Self::scan_range), but deserializes each value into a Token. An entry
+    /// that isn't a valid Token is yielded as an error without ending the iteration.
+    pub fn scan_tokens_range(start: &Vec<u8>, end: &Vec<u8>, partition_id: &str)
+                             -> Result<impl Iterator<Item=Result<(Vec<u8>, Token), DataError>>, DataError> {
+        let entries = Self::observe("scan_tokens_range", partition_id, || {
+            Self::stream_range("scan_tokens_range", Some(start.clone()), Some(end.clone()), partition_id)
+        })?;
+
+        Ok(entries.map(|entry| {
+            let (key, data) = entry?;
+            match deserialize_rmp_to::<Token>(&data) {
+                Err(err) => Err(DataError::DeserializationError(err.to_string())),
+                Ok(token) => Ok((key, token))
+            }
+        }))
+    }
+
+    /// Like scan_tokens_range, but entries that aren't valid Tokens are handled according to
+    /// `policy` (see [ScanErrorPolicy]).
+    pub fn scan_tokens_range_with_policy(start: &Vec<u8>, end: &Vec<u8>, partition_id: &str, policy: ScanErrorPolicy)
+                                         -> Result<TokenScan, DataError> {
+        Self::observe("scan_tokens_range", partition_id, || {
+            let entries = Self::stream_range("scan_tokens_range", Some(start.clone()), Some(end.clone()), partition_id)?;
+            Ok(TokenScan::new(entries, policy))
+        })
+    }
+
+    /// Creates `partition_id` and writes `seed` into it in a single batch. Fails if the
+    /// partition already exists; if seeding fails the half-created partition is destroyed,
+    /// so provisioning is all-or-nothing.
+    pub fn create_partition_with_seed(partition_id: &str, seed: Vec<(Vec<u8>, Vec<u8>)>)
+                                      -> Result<(), DataError> {
+        Self::observe("create_partition_with_seed", partition_id, || {
+            let Ok(_guard) = OPEN_LOCK.lock()
+                else { return Err(DataError::Poisoned) };
+
+            let factory = Self::get_db_factory();
+            let db = factory.create_db(partition_id)?;
+            let ops: Vec<BatchOp> = seed.into_iter()
+                .map(|(key, value)| BatchOp::Put(key, value))
+                .collect();
+
+            if let Err(err) = db.write_batch(&ops) {
+                drop(db);
+                let _ = factory.destroy_db(partition_id);
+                return Err(err);
+            }
+
+            let handles = Self::get_db_handles();
+            handles.insert(partition_id.to_string(), db);
+            handles.run_pending_tasks();
+            Ok(())
+        })
+    }
+
+    /// Loads `entries` by writing them straight to SST files and ingesting those, skipping the
+    /// memtable and WAL, which is far faster than saving them one by one for big initial loads.
+    /// The input is sorted and split into one key range per available core, each written to
+    /// its own file in parallel; since the ranges don't overlap the files can go straight to
+    /// the bottom level instead of piling into one hot write region. A key given more than
+    /// once keeps its last value, and existing values are overwritten. Returns the number of
+    /// distinct keys loaded. Fails with OperationNotAllowed if any key is an id counter or a
+    /// content refcount (see next_id), loading nothing.
+    pub fn bulk_load(entries: Vec<(Vec<u8>, Vec<u8>)>, partition_id: &str) -> Result<u64, DataError> {
+        Self::bulk_load_with(entries, partition_id, DuplicateKeys::LastWins).map(|report| report.keys_loaded)
+    }
+
+    /// Like bulk_load, with `duplicates` deciding what happens to keys given more than once.
+    /// Under DuplicateKeys::Reject nothing is loaded if there are any.
+    pub fn bulk_load_with(mut entries: Vec<(Vec<u8>, Vec<u8>)>, partition_id: &str, duplicates: DuplicateKeys)
+                          -> Result<BulkLoadReport, DataError> {
+        Self::observe("bulk_load", partition_id, || {
+            // a stable sort keeps duplicates in input order, so the last one given wins
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            let mut report = BulkLoadReport::default();
+            let mut unique: Vec<(Vec<u8>, Vec<u8>)> = Vec::with_capacity(entries.len());
+            for entry in entries {
+                match unique.last_mut() {
+                    Some(last) if last.0 == entry.0 => {
+                        if report.duplicate_keys.last() != Some(&entry.0) { report.duplicate_keys.push(entry.0.clone()); }
+                        *last = entry;
+                    },
+                    _ => unique.push(entry)
+                }
+            }
+
+            match duplicates {
+                DuplicateKeys::Reject if !report.duplicate_keys.is_empty() =>
+                    return Err(DataError::DuplicateKeys(report.duplicate_keys)),
+                DuplicateKeys::LastWins => report.duplicate_keys.clear(),
+                _ => {}
+            }
+
+            let entries = unique;
+            if entries.is_empty() { return Ok(report); }
+
+            let shard_count = thread::available_parallelism().map_or(1, |cores| cores.get());
+            let shards: Vec<&[(Vec<u8>, Vec<u8>)]> = entries.chunks(entries.len().div_ceil(shard_count)).collect();
+            Self::get_db(partition_id)?.ingest(&shards)?;
+
+            // with each key's lock taken, a cache-miss load that read the pre-ingest value has
+            // either finished (and is invalidated here) or hasn't started and will see the new one
+            for (key, _) in &entries {
+                let _guard = locks::lock_key(key, partition_id);
+                Self::invalidate_cache_entry(key, partition_id);
+            }
+            report.keys_loaded = entries.len() as u64;
+            Ok(report)
+        })
+    }
+
+    /// Saves `fields` as one document, all in a single atomic batch: each field under its own
+    /// key (`doc_key`, a 0 byte, then the field name) plus a manifest of the field names under
+    /// `doc_key` and the 0 byte. Fields of an earlier version that aren't in `fields` are
+    /// deleted. Since field keys extend `doc_key`, a doc_key containing a 0 byte can collide
+    /// with another document's fields.
+    pub fn save_document(doc_key: &Vec<u8>, fields: HashMap<String, Vec<u8>>, partition_id: &str)
+                         -> Result<(), DataError> {
+        Self::observe("save_document", partition_id, || {
+            let mut names: Vec<&String> = fields.keys().collect();
+            names.sort();
+            let manifest = match serialize_to_bytes_rmp(&names) {
+                Err(err) => return Err(DataError::SerializationError(err.to_string())),
+                Ok(manifest) => manifest
+            };
+
+            let mut batch = DataBatch::new(partition_id);
+            let new_keys: HashSet<Vec<u8>> = names.iter().map(|name| document_field_key(doc_key, name)).collect();
+            for key in Self::document_keys(doc_key, partition_id)? {
+                if !new_keys.contains(&key) { batch.delete(key); }
+            }
+
+            batch.put(document_prefix(doc_key), manifest);
+            for (name, data) in fields { batch.put(document_field_key(doc_key, &name), data); }
+            batch.apply()
+        })
+    }
+
+    /// The fields of the document saved under `doc_key`, read in one prefix scan of a single
+    /// snapshot, so they always come from the same save. DataNotFound if there's no such
+    /// document.
+    pub fn get_document(doc_key: &Vec<u8>, partition_id: &str) -> Result<HashMap<String, Vec<u8>>, DataError> {
+        Self::observe("get_document", partition_id, || {
+            let db = Self::get_db(partition_id)?;
+            let prefix = document_prefix(doc_key);
+            let end = prefix_end(&prefix);
+
+            // the manifest's key is the prefix itself, so if it exists it's the first entry
+            let mut manifest: Option<HashSet<String>> = None;
+            let mut result = Ok(());
+            let mut fields = HashMap::new();
+            db.scan(Some(prefix.as_slice()), end.as_deref(), &mut |key, value| {
+                let Some(names) = &manifest else {
+                    if key.len() != prefix.len() { return false; }
+                    match deserialize_rmp_to::<HashSet<String>>(value) {
+                        Err(err) => result = Err(DataError::DeserializationError(err.to_string())),
+                        Ok(names) => manifest = Some(names)
+                    }
+                    return result.is_ok();
+                };
+
+                // anything not in the manifest is a leftover no save has cleaned up yet
+                if let Ok(name) = String::from_utf8(key[prefix.len()..].to_vec()) {
+                    if names.contains(&name) { fields.insert(name, value.to_vec()); }
+                }
+                true
+            })?;
+
+            result?;
+            manifest.map(|_| fields).ok_or(DataError::DataNotFound)
+        })
+    }
+
+    /// Deletes the document saved under `doc_key`, manifest and fields alike, in one atomic
+    /// batch.
+    pub fn delete_document(doc_key: &Vec<u8>, partition_id: &str) -> Result<(), DataError> {
+        Self::observe("delete_document", partition_id, || {
+            let mut batch = DataBatch::new(partition_id);
+            for key in Self::document_keys(doc_key, partition_id)? { batch.delete(key); }
+            batch.apply()
+        })
+    }
+
+    /// Streams a point-in-time snapshot of the partition to `writer` as length-prefixed
+    /// key/value records, values exactly as stored (so an encrypted partition's backup stays
+    /// encrypted, and restoring needs the same keys and dictionaries). A checkpoint record is
+    /// written every 1024 keys and reported to `progress`. If the backup is interrupted, call
+    /// this again with `resume_after` set to the last reported checkpoint, after discarding
+    /// whatever the interrupted stream wrote past that checkpoint; the resumed part reads from
+    /// a new snapshot, so only each part on its own is point-in-time.
+    pub fn backup_to(partition_id: &str, writer: impl Write, resume_after: Option<&Vec<u8>>,
+                     progress: impl FnMut(BackupProgress)) -> Result<BackupProgress, DataError> {
+        Self::observe("backup_to", partition_id, || {
+            let db = Self::get_db(partition_id)?;
+            backup::write_backup(db.as_ref(), writer, resume_after.map(|key| key.as_slice()), progress)
+        })
+    }
+
+    /// Writes every live entry of `partition_id` to `writer` in a backend-neutral format, for
+    /// moving a partition to another backend: a manifest (format version and key count), each
+    /// key with its value decoded (so uncompressed and unencrypted; expiry times aren't kept),
+    /// then a crc32 of all of it. Id counters and content refcounts, being plain numbers the
+    /// codec never touches, go out and back in as stored, so next_id and release_content carry
+    /// on where they left off after an import. The partition is read twice, the first time to
+    /// count its keys for the manifest, and the export fails if the count changed in between,
+    /// so export a partition that isn't being written to (or a checkpoint of one). Returns the
+    /// manifest.
+    pub fn export_portable(partition_id: &str, writer: impl Write) -> Result<PortableManifest, DataError> {
+        Self::observe("export_portable", partition_id, || {
+            let db = Self::get_db(partition_id)?;
+            portable::export(db.as_ref(), writer)
+        })
+    }
+
+    /// Creates `partition_id` and loads an export_portable stream into it, values encoded as
+    /// the partition is configured to. The manifest is checked before anything is loaded, and
+    /// the key count and checksum once the stream ends; if a check or a write fails the
+    /// partition is destroyed again, so a bad stream leaves nothing behind. Fails if the
+    /// partition already exists, and the partition can't be opened until the import is done.
+    pub fn import_portable(partition_id: &str, reader: impl Read) -> Result<PortableManifest, DataError> {
+        Self::observe("import_portable", partition_id, || {
+            let factory = Self::get_db_factory();
+            let db = {
+                let Ok(_guard) = OPEN_LOCK.lock()
+                    else { return Err(DataError::Poisoned) };
+                factory.create_db(partition_id)?
+            };
+
+            // the open lock isn't held while loading, so other partitions can open meanwhile
+            let manifest = match portable::import(db.as_ref(), reader) {
+                Err(err) => {
+                    drop(db);
+                    let _ = factory.destroy_db(partition_id);
+                    return Err(err);
+                },
+                Ok(manifest) => manifest
+            };
+
+            let Ok(_guard) = OPEN_LOCK.lock()
+                else { return Err(DataError::Poisoned) };
+            let handles = Self::get_db_handles();
+            handles.insert(partition_id.to_string(), db);
+            handles.run_pending_tasks();
+            Ok(manifest)
+        })
+    }
+
+    /// Bytes RocksDB wrote to SSTs (flushes plus compactions) per byte of user writes, since
+    /// the partition was opened. 0.0 until anything has been written. Requires
+    /// `enable_statistics` in the config.
+    pub fn write_amplification(partition_id: &str) -> Result<f64, DataError> {
+        Self::observe("write_amplification", partition_id, || {
+            Self::get_db(partition_id)?.write_amplification()
+        })
+    }
+
+    /// Total size in bytes of the partition's SST files, as RocksDB reports it. Doesn't count
+    /// the WAL or memtables, so recent writes show up once they're flushed.
+    pub fn partition_size(partition_id: &str) -> Result<u64, DataError> {
+        Self::observe("partition_size", partition_id, || Self::get_db(partition_id)?.disk_size())
+    }
+
+    /// Polls `partition_id`'s size (see partition_size) every `poll_every` on a background
+    /// thread, calling `callback` whenever it moves between the SizeLevels `thresholds`
+    /// defines, including back down, so alerts can be cleared. The first poll only calls back
+    /// if the partition is already past a threshold. Only open partitions are polled, so one
+    /// closed for being idle isn't reopened to check it. Replaces any earlier watch of the
+    /// partition.
+    pub fn watch_partition_size<F>(partition_id: &str, thresholds: SizeThresholds, poll_every: Duration, callback: F)
+        where F: Fn(&str, SizeLevel, u64) + Send + Sync + 'static {
+        size::watch(partition_id, thresholds, poll_every, Arc::new(callback))
+    }
+
+    /// Stops watching `partition_id`'s size, after at most one more poll.
+    pub fn unwatch_partition_size(partition_id: &str) {
+        size::unwatch(partition_id)
+    }
+
+    /// Compacts the whole partition (column families included) and then writes a checkpoint
+    /// of it to `dest`, which must not exist yet, returning once both are done. The
+    /// checkpoint is a consistent copy of the partition as of when it was taken, opened like
+    /// any other partition directory, and is made of hard links to the partition's files
+    /// where `dest` is on the same filesystem; compacting first means it holds as few (and as
+    /// compact) files as possible. Writes aren't blocked in the meantime; any made after the
+    /// compaction are still included, as a small extra file. Compacting a big
+    /// partition takes a while and rewrites all of it. Keys a router keeps in memory aren't
+    /// included, and a secondary partition fails with ReadOnly.
+    pub fn compacted_checkpoint(partition_id: &str, dest: impl AsRef<Path>) -> Result<(), DataError> {
+        Self::observe("compacted_checkpoint", partition_id, || {
+            let db = Self::get_db(partition_id)?;
+            db.compact()?;
+            db.checkpoint(dest.as_ref())
+        })
+    }
+
+    /// Stops the background flusher (after its last pass), then flushes and closes every open
+    /// partition. Call it before the process exits so nothing written is left only in
+    /// memtables or an unsynced WAL. Partitions touched afterwards are simply reopened (and
+    /// the flusher restarted). Returns the first flush error, having still closed everything.
+    pub fn shutdown() -> Result<(), DataError> {
+        flusher::stop();
+        let handles = Self::get_db_handles();
+        let mut result = Ok(());
+        for (partition_id, db) in handles.iter() {
+            if let Err(err) = db.flush() {
+                hooks::report_error(&err, &partition_id, "shutdown");
+                if result.is_ok() { result = Err(err); }
+            }
+        }
+        handles.invalidate_all();
+        handles.run_pending_tasks();
+        evicted_handles().lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clear();
+        result
+    }
+
+    /// Streams `partition_id`'s writes from sequence number `since` on, oldest first, read
+    /// from RocksDB's WAL, e.g. to feed incremental replication: remember the last record's
+    /// sequence and resume from the one after it. Puts, deletes and merges (appends, counters)
+    /// in the default keyspace are reported, singly or from batches. Namespaced and contract
+    /// writes aren't, though they still use up sequence numbers, so a stream can skip some;
+    /// nor are bulk loads (which bypass the WAL) or a routed partition's in-memory keys.
+    ///
+    /// Only what's still in the WAL can be streamed. RocksDB deletes WAL files once their
+    /// writes are flushed to SSTs, so set DataConfig::wal_ttl_seconds and/or wal_size_limit_mb
+    /// to keep them around for at least as long as a consumer can fall behind; asking for a
+    /// sequence that has been deleted fails rather than silently skipping ahead.
+    pub fn changes_since(partition_id: &str, since: u64) -> Result<ScanStream<ChangeRecord>, DataError> {
+        Self::observe("changes_since", partition_id, || {
+            let db = Self::get_db(partition_id)?;
+            Ok(ScanStream::new("changes_since", partition_id, move |emit| db.changes_since(since, emit)))
+        })
+    }
+
+    /// Stops RocksDB from starting background compactions in `partition_id` (column families
+    /// included) until resume_compaction, e.g. to keep compaction I/O out of a latency-sensitive
+    /// window. Compactions already running finish, flushes carry on, and compact calls still
+    /// compact. It lasts across the partition being closed and reopened. Don't leave it paused
+    /// for long under writes: L0 files pile up, and once there are too many (or too many bytes
+    /// await compaction) RocksDB slows writes down and then stops them altogether, until
+    /// compaction is resumed and catches up. This sets RocksDB's disable_auto_compactions
+    /// option on every column family rather than pausing all background work, so it only keeps
+    /// new compactions from being scheduled. A secondary partition fails with ReadOnly.
+    pub fn pause_compaction(partition_id: &str) -> Result<(), DataError> {
+        Self::observe("pause_compaction", partition_id, || Self::set_compaction_paused(partition_id, true))
+    }
+
+    /// Lets RocksDB compact `partition_id` in the background again after pause_compaction.
+    /// Does nothing if it wasn't paused.
+    pub fn resume_compaction(partition_id: &str) -> Result<(), DataError> {
+        Self::observe("resume_compaction", partition_id, || Self::set_compaction_paused(partition_id, false))
+    }
+
+    /// The partition's first and last keys, None if it's empty, found by seeking a RocksDB
+    /// iterator to either end rather than scanning, e.g. to plan ranges or split points.
+    /// Reserved keys (see next_id) count like any other, and so do entries whose expiry has
+    /// passed but that haven't been deleted yet. A routed partition is scanned in full.
+    pub fn key_bounds(partition_id: &str) -> Result<Option<(Vec<u8>, Vec<u8>)>, DataError> {
+        Self::observe("key_bounds", partition_id, || Self::get_db(partition_id)?.key_bounds())
+    }
+
+    /// Reads each (key, partition) in `items`, returning results in the same order, None for a
+    /// key without a value. Keys are grouped by partition and each group read in one RocksDB
+    /// multi_get, with at most `concurrency` partitions (at least 1) being opened and read at
+    /// once, so a read spread over many partitions neither opens them all together nor
+    /// floods the disk. Cached values are returned from the cache, but misses don't populate
+    /// it (as with get_data_no_cache). A partition that can't be opened fails all of its keys.
+    pub fn get_many_cross_partition(items: Vec<(Vec<u8>, String)>, concurrency: usize)
+                                    -> Vec<Result<Option<Vec<u8>>, DataError>> {
+        let item_count = items.len();
+        let mut groups: HashMap<String, Vec<(usize, Vec<u8>)>> = HashMap::new();
+        for (i, (key, partition_id)) in items.into_iter().enumerate() {
+            groups.entry(partition_id).or_default().push((i, key));
+        }
+
+        let worker_count = concurrency.max(1).min(groups.len());
+        let groups = Mutex::new(groups.into_iter());
+        let mut results: Vec<Option<Result<Option<Vec<u8>>, DataError>>> = (0..item_count).map(|_| None).collect();
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..worker_count).map(|_| scope.spawn(|| {
+                let mut read = vec![];
+                loop {
+                    let next = groups.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).next();
+                    let Some((partition_id, keys)) = next
+                        else { return read };
+                    read.extend(Self::get_many_in(&partition_id, keys));
+                }
+            })).collect();
+
+            for worker in workers {
+                let Ok(read) = worker.join()
+                    else { continue };
+                for (i, result) in read { results[i] = Some(result); }
+            }
+        });
+
+        // only a reader that panicked leaves results unfilled
+        results.into_iter()
+            .map(|result| result.unwrap_or(Err(DataError::FromStore("reader panicked".to_string()))))
+            .collect()
+    }
+
+    /// Whether `partition_id` currently has an open handle. Never opens the partition or
+    /// counts as an access to it, so checking doesn't keep an idle partition from being
+    /// closed. The answer can be stale by the time it's used.
+    pub fn is_open(partition_id: &str) -> bool {
+        Self::get_db_handles().contains_key(partition_id)
+    }
+
+    /// Closes `partition_id`'s handle, flushing it first if it can, so the next access opens
+    /// the partition afresh. This is how a partition recovers from running out of disk: once
+    /// space has been freed, reopening clears both RocksDB's own stopped-writes state and
+    /// the read-only mode of read_only_on_out_of_space. The partition's directory stays locked
+    /// until every thread still using the old handle is done with it, and opening fails until
+    /// then. Cached entries are kept, since the data itself doesn't change.
+    pub fn reopen_partition(partition_id: &str) {
+        let handles = Self::get_db_handles();
+        handles.invalidate(partition_id);
+        handles.run_pending_tasks();
+        evicted_handles().lock().unwrap_or_else(|poisoned| poisoned.into_inner()).remove(partition_id);
+    }
+
+    /// Brings a secondary partition (see PartitionConfig::secondary_path) up to date with
+    /// everything its primary has written so far, then drops the partition's cache entries so
+    /// reads see it. Does nothing for a primary partition.
+    pub fn catch_up(partition_id: &str) -> Result<(), DataError> {
+        Self::observe("catch_up", partition_id, || {
+            if Self::get_db(partition_id)?.catch_up()? {
+                Self::invalidate_partition(partition_id);
+                replica_lags().write().unwrap_or_else(|poisoned| poisoned.into_inner()).remove(partition_id);
+            }
+            Ok(())
+        })
+    }
+
+    /// How far a secondary partition is behind its primary: the primary's latest sequence
+    /// number less the one the secondary has caught up to, i.e. about how many key writes
+    /// catch_up would replay. The primary's side is read through a second secondary instance
+    /// kept under `secondary_path`/lag_probe, opened on the first call and caught up on each
+    /// one after, so this costs about as much as a catch_up (reads are unaffected). The result
+    /// is what max_replica_lag checks reads against. Always 0 for a primary.
+    pub fn replica_lag(partition_id: &str) -> Result<u64, DataError> {
+        Self::observe("replica_lag", partition_id, || {
+            let lag = Self::get_db(partition_id)?.replica_lag()?;
+            replica_lags().write().unwrap_or_else(|poisoned| poisoned.into_inner())
+                .insert(partition_id.to_string(), lag);
+            Ok(lag)
+        })
+    }
+
+    /// How full the block cache `partition_id` reads through is. With `block_cache_mb` set
+    /// that cache is shared, so every partition reports the same numbers.
+    pub fn block_cache_usage(partition_id: &str) -> Result<BlockCacheUsage, DataError> {
+        Self::observe("block_cache_usage", partition_id, || Self::get_db(partition_id)?.block_cache_usage())
+    }
+
+    /// An isolated keyspace for `contract_id` within `partition_id`.
+    pub fn contract_store(partition_id: &str, contract_id: &str) -> ContractStore {
+        ContractStore::new(partition_id, contract_id)
+    }
+
+    /// The value stored for `id` in `namespace`, under the key [composite_key] gives them.
+    /// Cached like any other data.
+    pub fn get_by(namespace: &[u8], id: &[u8], partition_id: &str) -> Result<Arc<RwLock<Vec<u8>>>, DataError> {
+        Self::get_data(&composite_key(namespace, id), partition_id)
+    }
+
+    pub fn save_by(namespace: &[u8], id: &[u8], data: Vec<u8>, partition_id: &str) -> Result<(), DataError> {
+        Self::save_data(&composite_key(namespace, id), data, partition_id)
+    }
+
+    /// Streams every (id, value) in `namespace`, in id order, from a snapshot (see
+    /// [ScanStream]). Entries of other namespaces never show up, whatever their names.
+    pub fn scan_namespace(namespace: &[u8], partition_id: &str) -> Result<ScanStream<(Vec<u8>, Vec<u8>)>, DataError> {
+        Self::observe("scan_namespace", partition_id, || {
+            let db = Self::get_db(partition_id)?;
+            let prefix = namespace_prefix(namespace);
+            let end = prefix_end(&prefix);
+            Ok(ScanStream::new("scan_namespace", partition_id, move |emit| {
+                db.scan(Some(prefix.as_slice()), end.as_deref(), &mut |key, value| {
+                    emit((key[prefix.len()..].to_vec(), value.to_vec()))
+                })
+            }))
+        })
+    }
+
+    /// A logical table named `name` within `partition_id` (see [Table]).
+    pub fn table(name: &str, partition_id: &str) -> Table {
+        Table::new(name, partition_id)
+    }
+
+    /// Streams every key in the partition, in order, without reading values out of the store.
+    pub fn iter_keys(partition_id: &str) -> Result<ScanStream<Vec<u8>>, DataError> {
+        Self::observe("iter_keys", partition_id, || {
+            let db = Self::get_db(partition_id)?;
+            Ok(ScanStream::new("iter_keys", partition_id, move |emit| {
+                db.scan_keys(None, None, &mut |key| emit(key.to_vec()))
+            }))
+        })
+    }
+
+    /// Deletes `deletes` and writes `inserts` in one atomic batch, so readers never see the
+    /// old keys gone without the new ones in place.
+    pub fn replace_set(deletes: Vec<Vec<u8>>, inserts: Vec<(Vec<u8>, Vec<u8>)>, partition_id: &str)
+                       -> Result<(), DataError> {
+        let mut batch = DataBatch::new(partition_id);
+        for key in deletes { batch.delete(key); }
+        for (key, data) in inserts { batch.put(key, data); }
+        Self::observe("replace_set", partition_id, || batch.apply())
+    }
+
+    /// Reads every entry of the partition with checksum verification, returning how many
+    /// entries it checked or the first corruption found.
+    pub fn verify_partition(partition_id: &str) -> Result<u64, DataError> {
+        Self::observe("verify_partition", partition_id, || Self::get_db(partition_id)?.verify())
+    }
+
+    /// Current values of the provider's operation counters (cache hits/misses, db reads,
+    /// writes and scans), e.g. for asserting exactly how many db reads a test caused.
+    pub fn stats_snapshot() -> StatsSnapshot {
+        stats::snapshot()
+    }
+
+    /// Zeroes every counter. Operations running concurrently with a reset may or may not be
+    /// counted, so tests should reset before starting their work.
+    pub fn reset_stats() {
+        stats::reset();
+        latency::reset();
+        contention::reset()
+    }
+
+    /// Up to `n` of `partition_id`'s keys whose lock acquisitions most often had to wait,
+    /// most contended first, with how many sampled acquisitions waited (see
+    /// lock_contention_sample_rate). Empty unless sampling is on. Counts are since start-up
+    /// or the last reset_stats, and are halved now and then to make room once lots of keys
+    /// have been seen. Keys share lock stripes, so a rarely used key can show up here for
+    /// waiting behind a hot one.
+    pub fn contended_keys(partition_id: &str, n: usize) -> Vec<(Vec<u8>, u64)> {
+        contention::contended_keys(partition_id, n)
+    }
+
+    /// p50/p95/p99 latencies of get_token, save_token, get_data and save_data calls on
+    /// `partition_id` (their variants included, e.g. save_data_ref counts as save_data),
+    /// measured around the whole call, cache lookups and lock waits included.
+    pub fn latency_snapshot(partition_id: &str) -> LatencySnapshot {
+        latency::snapshot(partition_id)
+    }
+
+    /// Trains a zstd dictionary of at most `max_size` bytes from sample values, for use in a
+    /// PartitionConfig. A few hundred representative samples is usually plenty.
+    pub fn train_zstd_dictionary(samples: &[Vec<u8>], max_size: usize) -> Result<Vec<u8>, DataError> {
+        zstd::dict::from_samples(samples, max_size)
+            .map_err(|err| DataError::SerializationError(err.to_string()))
+    }
+
+    /// Loads every entry under `prefix` into the data cache in one ordered scan, returning how
+    /// many were cached. Entries land in the data cache only; tokens are still deserialized
+    /// and cached on their first get_token.
+    pub fn warm_prefix(prefix: &Vec<u8>, partition_id: &str) -> Result<u64, DataError> {
+        Self::observe("warm_prefix", partition_id, || {
+            let db = Self::get_db(partition_id)?;
+            let end = prefix_end(prefix);
+            let mut warmed = 0;
+            db.scan(Some(prefix.as_slice()), end.as_deref(), &mut |key, value| {
+                Self::put_in_data_cache(&key.to_vec(), partition_id, value.to_vec());
+                warmed += 1;
+                true
+            })?;
+            Ok(warmed)
+        })
+    }
+
+    /// Like warm_prefix, but only caches the entries whose (decoded) value passes `predicate`,
+    /// e.g. just the active tokens, returning how many were cached.
+    pub fn warm_prefix_where(prefix: &Vec<u8>, partition_id: &str, predicate: impl Fn(&[u8]) -> bool)
+                             -> Result<u64, DataError> {
+        Self::observe("warm_prefix_where", partition_id, || {
+            let db = Self::get_db(partition_id)?;
+            let end = prefix_end(prefix);
+            let mut warmed = 0;
+            db.scan(Some(prefix.as_slice()), end.as_deref(), &mut |key, value| {
+                if !predicate(value) { return true; }
+                Self::put_in_data_cache(&key.to_vec(), partition_id, value.to_vec());
+                warmed += 1;
+                true
+            })?;
+            Ok(warmed)
+        })
+    }
+
+    /// Size in bytes of the value stored under `key` as it sits in the store (i.e. after any
+    /// compression or encryption), or None if there's no such key. Bypasses the cache.
+    pub fn data_size(key: &Vec<u8>, partition_id: &str) -> Result<Option<usize>, DataError> {
+        Self::observe("data_size", partition_id, || Self::get_db(partition_id)?.value_size(key))
+    }
+
+    pub fn save_typed_data<T: Serialize>(key: &Vec<u8>, data: &T, partition_id: &str) -> Result<(), DataError> {
+        Self::observe("save_typed_data", partition_id, || Self::save_serialized(key, data, None, partition_id))
+    }
+
+    /// Like save_typed_data, also recording a type tag in the value's header for peek_type:
+    /// `type_tag`, or `std::any::type_name::<T>()` if that's None. Type names aren't stable
+    /// across compiler versions or refactors, so give an explicit tag for anything that has
+    /// to outlive the build that wrote it.
+    pub fn save_typed_data_tagged<T: Serialize>(key: &Vec<u8>, data: &T, type_tag: Option<&str>, partition_id: &str)
+                                                -> Result<(), DataError> {
+        let type_tag = type_tag.unwrap_or(std::any::type_name::<T>());
+        Self::observe("save_typed_data", partition_id, || Self::save_serialized(key, data, Some(type_tag), partition_id))
+    }
+
+    /// The type tag the value under `key` was saved with by save_typed_data_tagged, or None
+    /// for a value saved any other way, without deserializing (or even reading past the header
+    /// of) the value. Bypasses the cache.
+    pub fn peek_type(key: &Vec<u8>, partition_id: &str) -> Result<Option<String>, DataError> {
+        Self::observe("peek_type", partition_id, || Self::get_db(partition_id)?.type_tag(key))
+    }
+
+    pub fn save_locked_data<T: Serialize>(key: &Vec<u8>, data: Arc<RwLock<T>>, partition_id: &str)
+                                                 -> Result<(), DataError> {
+        // let db = Self::get_db(partition_id)?;
+        // let Ok(write_data) = data.write()
+        //     else { return Err(DataError::Poisoned) };
+        // let Ok(serialized) = serialize_to_bytes_rmp(write_data.deref())
+        //     else { return Err(DataError::SerializationError) };
+        //
+        // let _ = db.save_data(key, &serialized)?;
+        // Self::put_in_data_cache(key, Arc::new(RwLock::new(serialized)));
+        Ok(())
+    }
+
+    /// Serializes `items` in parallel on rayon's thread pool, then writes them (and updates
+    /// the data cache) in one atomic batch. With SerializeFailures::Abort the first item that
+    /// fails to serialize fails the whole call and nothing is written; with Skip the rest are
+    /// written and the failures come back in the report.
+    pub fn save_typed_batch<T: Serialize + Sync>(items: Vec<(Vec<u8>, T)>, partition_id: &str,
+                                                 on_failure: SerializeFailures) -> Result<TypedBatchReport, DataError> {
+        Self::observe("save_typed_batch", partition_id, || {
+            let serialized: Vec<Result<Vec<u8>, DataError>> = items.par_iter()
+                .map(|(_, item)| serialize_to_bytes_rmp(item)
+                    .map_err(|err| DataError::SerializationError(err.to_string())))
+                .collect();
+
+            let mut report = TypedBatchReport::default();
+            let mut batch = DataBatch::new(partition_id);
+            for ((key, _), result) in items.into_iter().zip(serialized) {
+                match result {
+                    Err(err) if on_failure == SerializeFailures::Abort => return Err(err),
+                    Err(err) => report.failures.push((key, err)),
+                    Ok(data) => {
+                        batch.put(key, data);
+                        report.items_written += 1;
+                    }
+                }
+            }
+
+            if !batch.is_empty() { batch.apply()?; }
+            Ok(report)
+        })
+    }
+
+    fn observe<T>(op: &'static str, partition_id: &str, f: impl FnOnce() -> Result<T, DataError>)
+                  -> Result<T, DataError> {
+        let started = latency::is_tracked(op).then(Instant::now);
+        let call = ObservedCall::enter();
+        let result = scope::metered(f);
+        if let Some(started) = started { latency::record(partition_id, op, started.elapsed()); }
+        // a public call made by another one fails it too, so only the outer call reports
+        if let (Err(err), true) = (&result, call.outermost) { hooks::report_error(err, partition_id, op); }
+        result
+    }
+
+    fn load_data(key: &Vec<u8>, partition_id: &str) -> Result<Arc<RwLock<Vec<u8>>>, DataError> {
+        // the cache only holds immutable values then, so the caller gets a lock of its own
+        if Self::get_config().immutable_data_cache {
+            return Ok(Arc::new(RwLock::new(Self::load_shared(key, partition_id)?.to_vec())));
+        }
+
+        let cache = Self::get_data_cache();
+        let cache_key = cache_key(key, partition_id);
+        if let Some(data_entry) = cache.get(&cache_key) {
+            stats::record(Stat::DataCacheHit);
+            return Ok(data_entry.clone());
+        }
+
+        stats::record(Stat::DataCacheMiss);
+
+        let db = Self::get_db(partition_id)?;
+        let (data, expires_at, _guard) = Self::read_through(db.as_ref(), key, partition_id)?;
+        // the cache can't expire an entry at a given time, so expiring values aren't cached
+        if expires_at.is_some() { return Ok(Arc::new(RwLock::new(data))); }
+        if let Some(data_entry) = cache.get(&cache_key) { return Ok(data_entry); }
+        Self::put_in_data_cache(key, partition_id, data);
+        cache.get(&cache_key).ok_or(DataError::CacheError)
+    }
+
+    // Reads a miss and returns it with the key's lock held, for the caller's cache update. A
+    // store read happens under the lock; the partition's loader, if it has one, runs before
+    // the lock is taken, since it may call back into the provider for keys sharing the lock's
+    // stripe, so the caller has to re-check the cache. The expiry is whatever the loader's
+    // last store read saw, so an expiring value it passes on isn't cached either.
+    fn read_through(db: &dyn Db, key: &Vec<u8>, partition_id: &str)
+                    -> Result<(Vec<u8>, Option<SystemTime>, MutexGuard<'static, ()>), DataError> {
+        let Some(loader) = hooks::loader(partition_id)
+            else {
+                let guard = locks::lock_key(key, partition_id);
+                let (data, expires_at) = db.get_data_with_expiry(key)?;
+                return Ok((data, expires_at, guard));
+            };
+
+        let expires_at = Cell::new(None);
+        let read_store = || {
+            let (data, expiry) = db.get_data_with_expiry(key)?;
+            expires_at.set(expiry);
+            Ok(data)
+        };
+        let data = loader(key, &read_store)?;
+        Ok((data, expires_at.get(), locks::lock_key(key, partition_id)))
+    }
+
+    fn load_shared(key: &Vec<u8>, partition_id: &str) -> Result<Arc<Vec<u8>>, DataError> {
+        if !Self::get_config().immutable_data_cache {
+            let entry = Self::load_data(key, partition_id)?;
+            let Ok(data) = contention::read(&entry, key, partition_id)
+                else { return Err(DataError::Poisoned) };
+            return Ok(Arc::new(data.clone()));
+        }
+
+        let cache = Self::get_shared_data_cache();
+        let cache_key = cache_key(key, partition_id);
+        if let Some(shared) = cache.get(&cache_key) {
+            stats::record(Stat::DataCacheHit);
+            return Ok(shared);
+        }
+
+        stats::record(Stat::DataCacheMiss);
+
+        let db = Self::get_db(partition_id)?;
+        let (data, expires_at, _guard) = Self::read_through(db.as_ref(), key, partition_id)?;
+        if expires_at.is_some() { return Ok(Arc::new(data)); }
+        if let Some(shared) = cache.get(&cache_key) { return Ok(shared); }
+        let shared = Arc::new(data);
+        cache.insert(cache_key, shared.clone());
+        Ok(shared)
+    }
+
+    fn save_serialized<T: Serialize>(key: &Vec<u8>, data: &T, type_tag: Option<&str>, partition_id: &str)
+                                     -> Result<(), DataError> {
+        let serialized = match serialize_to_bytes_rmp(data) {
+            Err(err) => return Err(DataError::SerializationError(err.to_string())),
+            Ok(serialized) => serialized
+        };
+
+        let db = Self::get_db(partition_id)?;
+        let _guard = locks::lock_key(key, partition_id);
+        match type_tag {
+            None => db.save_data(key, &serialized)?,
+            Some(type_tag) => db.save_data_tagged(key, &serialized, type_tag)?
+        }
+        Self::cache_written_data(key, partition_id, serialized);
+        Ok(())
+    }
+
+    fn load_typed_data<T: DeserializeOwned>(key: &Vec<u8>, partition_id: &str) -> Result<T, DataError> {
+        if Self::get_config().immutable_data_cache {
+            let data = Self::load_shared(key, partition_id)?;
+            return match deserialize_rmp_to::<T>(&data[..]) {
+                Err(err) => Err(DataError::DeserializationError(err.to_string())),
+                Ok(typed) => Ok(typed)
+            };
+        }
+
+        let entry = Self::load_data(key, partition_id)?;
+        let Ok(data) = contention::read(&entry, key, partition_id)
+            else { return Err(DataError::Poisoned) };
+        match deserialize_rmp_to::<T>(&data[..]) {
+            Err(err) => Err(DataError::DeserializationError(err.to_string())),
+            Ok(typed) => Ok(typed)
+        }
+    }
+
+    fn stream_range(op: &'static str, start: Option<Vec<u8>>, end: Option<Vec<u8>>, partition_id: &str)
+                    -> Result<ScanStream<(Vec<u8>, Vec<u8>)>, DataError> {
+        let db = Self::get_db(partition_id)?;
+        Ok(ScanStream::new(op, partition_id, move |emit| {
+            db.scan(start.as_deref(), end.as_deref(), &mut |key, value| emit((key.to_vec(), value.to_vec())))
+        }))
+    }
+
+    fn document_keys(doc_key: &Vec<u8>, partition_id: &str) -> Result<Vec<Vec<u8>>, DataError> {
+        let db = Self::get_db(partition_id)?;
+        let prefix = document_prefix(doc_key);
+        let end = prefix_end(&prefix);
+        let mut keys = vec![];
+        db.scan_keys(Some(prefix.as_slice()), end.as_deref(), &mut |key| {
+            keys.push(key.to_vec());
+            true
+        })?;
+        Ok(keys)
+    }
+
+    fn get_token_from_db(key: &Vec<u8>, partition_id: &str) -> Result<Token, DataError> {
+        let db = Self::get_db(partition_id)?;
+        db.get_token(key)
+    }
+
+    fn set_compaction_paused(partition_id: &str, paused: bool) -> Result<(), DataError> {
+        let db = Self::get_db(partition_id)?;
+        let mut paused_partitions = compaction_paused().lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        db.set_auto_compaction(!paused)?;
+        match paused {
+            true => paused_partitions.insert(partition_id.to_string()),
+            false => paused_partitions.remove(partition_id)
+        };
+        Ok(())
+    }
+
+    fn check_replica_lag(partition_id: &str) -> Result<(), DataError> {
+        let Some(max_lag) = Self::get_config().partitions.get(partition_id).and_then(|p| p.max_replica_lag)
+            else { return Ok(()) };
+        let lags = replica_lags().read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        match lags.get(partition_id) {
+            Some(&lag) if lag > max_lag => Err(DataError::ReplicaBehind(lag)),
+            _ => Ok(())
+        }
+    }
+
+    fn get_many_in(partition_id: &str, keys: Vec<(usize, Vec<u8>)>) -> Vec<(usize, Result<Option<Vec<u8>>, DataError>)> {
+        let read = Self::observe("get_many_cross_partition", partition_id, || {
+            let mut read = Vec::with_capacity(keys.len());
+            let mut misses = vec![];
+            for (i, key) in &keys {
+                let cache_key = cache_key(key, partition_id);
+                if let Some(data_entry) = Self::get_data_cache().get(&cache_key) {
+                    stats::record(Stat::DataCacheHit);
+                    let Ok(data) = data_entry.read()
+                        else { return Err(DataError::Poisoned) };
+                    read.push((*i, Ok(Some(data.clone()))));
+                } else if let Some(shared) = Self::get_shared_data_cache().get(&cache_key) {
+                    stats::record(Stat::DataCacheHit);
+                    read.push((*i, Ok(Some(shared.to_vec()))));
+                } else {
+                    stats::record(Stat::DataCacheMiss);
+                    misses.push((*i, key));
+                }
+            }
+
+            if misses.is_empty() { return Ok(read); }
+            let db = Self::get_db(partition_id)?;
+            let miss_keys: Vec<&Vec<u8>> = misses.iter().map(|(_, key)| *key).collect();
+            read.extend(misses.iter().map(|(i, _)| *i).zip(db.multi_get(&miss_keys)));
+            Ok(read)
+        });
+
+        match read {
+            Err(err) => keys.into_iter().map(|(i, _)| (i, Err(err.clone()))).collect(),
+            Ok(read) => read
+        }
+    }
+
+    fn ring_head(db: &dyn Db, head_key: &Vec<u8>) -> Result<Option<(u64, u32)>, DataError> {
+        match db.get_data(head_key) {
+            Err(DataError::DataNotFound) => Ok(None),
+            Err(err) => Err(err),
+            Ok(stored) => merge::ring_head(&stored)
+                .map(Some)
+                .ok_or_else(|| DataError::DeserializationError("ring head is corrupt".to_string()))
+        }
+    }
+
+    fn sync_open_partitions() {
+        for (partition_id, db) in Self::get_db_handles().iter() {
+            if let Err(err) = db.sync_wal() { hooks::report_error(&err, &partition_id, "auto_flush"); }
+        }
+    }
+
+    fn open_partition_size(partition_id: &str) -> Option<u64> {
+        Self::get_db_handles().get(partition_id)?.disk_size().ok()
+    }
+
+    fn get_db(partition_id: &str) -> Result<Arc<dyn Db>, DataError> {
+        let handles = Self::get_db_handles();
+        if let Some(db) = handles.get(partition_id) { return Ok(db); }
+
+        // RocksDB locks the partition directory, so two threads must never race to open it
+        let Ok(_guard) = OPEN_LOCK.lock()
+            else { return Err(DataError::Poisoned) };
+        if let Some(db) = handles.get(partition_id) { return Ok(db); }
+
+        // an evicted handle someone still holds keeps the directory locked, so it has to be
+        // taken back rather than a second instance opened
+        let evicted = evicted_handles().lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+            .remove(partition_id)
+            .and_then(|db| db.upgrade());
+        let db = match evicted {
+            Some(db) => db,
+            None => Self::get_db_factory().get_db(partition_id)?
+        };
+        if let Some(interval) = Self::get_config().auto_flush_interval { flusher::ensure_started(interval); }
+        handles.insert(partition_id.to_string(), db.clone());
+        // evict (and flush) the least-recently-used handle now rather than on some later access
+        handles.run_pending_tasks();
+        Ok(db)
+    }
+
+    // A lazy partition keeps its Tokens in the lazy cache instead, already deserialized
+    // when it's handed one.
+    fn put_in_token_cache(key: &Vec<u8>, partition_id: &str, data: Arc<RwLock<Token>>) {
+        match Self::has_lazy_tokens(partition_id) {
+            true => Self::get_lazy_token_cache().insert(cache_key(key, partition_id), Arc::new(LazyToken::loaded(data))),
+            false => Self::get_token_cache().insert(cache_key(key, partition_id), data)
+        }
+    }
+
+    fn load_token(key: &Vec<u8>, partition_id: &str) -> Result<Arc<RwLock<Token>>, DataError> {
+        if Self::has_lazy_tokens(partition_id) { return Self::load_lazy_token(key, partition_id)?.get(); }
+
+        let cache = Self::get_token_cache();
+        let cache_key = cache_key(key, partition_id);
+        if let Some(token_entry) = cache.get(&cache_key) {
+            stats::record(Stat::TokenCacheHit);
+            return Ok(token_entry.clone());
+        }
+
+        stats::record(Stat::TokenCacheMiss);
+
+        // locked so a save landing between the read and the insert can't be overwritten
+        // in the cache by the value it replaced
+        let _guard = locks::lock_key(key, partition_id);
+        let token = Self::get_token_from_db(key, partition_id)?;
+        Self::put_in_token_cache(key, partition_id, Arc::new(RwLock::new(token)));
+        cache.get(&cache_key).ok_or(DataError::CacheError)
+    }
+
+    fn load_lazy_token(key: &Vec<u8>, partition_id: &str) -> Result<Arc<LazyToken>, DataError> {
+        let cache = Self::get_lazy_token_cache();
+        let cache_key = cache_key(key, partition_id);
+        if let Some(lazy_token) = cache.get(&cache_key) {
+            stats::record(Stat::TokenCacheHit);
+            return Ok(lazy_token);
+        }
+
+        stats::record(Stat::TokenCacheMiss);
+
+        let db = Self::get_db(partition_id)?;
+        let _guard = locks::lock_key(key, partition_id);
+        let lazy_token = Arc::new(LazyToken::new(db.get_data(key)?));
+        cache.insert(cache_key, lazy_token.clone());
+        Ok(lazy_token)
+    }
+
+    fn has_lazy_tokens(partition_id: &str) -> bool {
+        Self::get_config().partitions.get(partition_id).is_some_and(|partition| partition.lazy_tokens)
+    }
+
+    fn put_in_data_cache(key: &Vec<u8>, partition_id: &str, data: Vec<u8>) {
+        match Self::get_config().immutable_data_cache {
+            true => Self::get_shared_data_cache().insert(cache_key(key, partition_id), Arc::new(data)),
+            false => Self::get_data_cache().insert(cache_key(key, partition_id), Self::to_cached_value(data))
+        }
+    }
+
+    // Writes go through these rather than straight into the cache so cache_insert_rate_limit
+    // can hold them back. A write that isn't cached still has to drop the entry it replaced.
+    fn cache_written_token(key: &Vec<u8>, partition_id: &str, token_ref: Arc<RwLock<Token>>) {
+        match Self::cache_insert_admitted() {
+            true => Self::put_in_token_cache(key, partition_id, token_ref),
+            false => Self::invalidate_token_entry(&cache_key(key, partition_id))
+        }
+    }
+
+    fn cache_written_data(key: &Vec<u8>, partition_id: &str, data: Vec<u8>) {
+        match Self::cache_insert_admitted() {
+            true => Self::put_in_data_cache(key, partition_id, data),
+            false => Self::invalidate_data_entry(&cache_key(key, partition_id))
+        }
+    }
+
+    fn cache_insert_admitted() -> bool {
+        let Some(per_second) = Self::get_config().cache_insert_rate_limit
+            else { return true };
+        let admitted = throttle::admit(per_second);
+        if !admitted { stats::record(Stat::CacheInsertThrottled); }
+        admitted
+    }
+
+    fn to_cached_value(data: Vec<u8>) -> Arc<RwLock<Vec<u8>>> {
+        match Self::get_config().intern_cache_values {
+            true => VALUE_POOL.get_or_init(ValuePool::new).intern(data),
+            false => Arc::new(RwLock::new(data))
+        }
+    }
+
+    fn invalidate_cache_entry(key: &Vec<u8>, partition_id: &str) {
+        let cache_key = cache_key(key, partition_id);
+        Self::invalidate_token_entry(&cache_key);
+        Self::invalidate_data_entry(&cache_key);
+    }
+
+    fn invalidate_token_entry(cache_key: &CacheKey) {
+        Self::get_token_cache().invalidate(cache_key);
+        Self::get_lazy_token_cache().invalidate(cache_key);
+    }
+
+    // only one of the data caches is ever filled, but it's cheap to clear both
+    fn invalidate_data_entry(cache_key: &CacheKey) {
+        Self::get_data_cache().invalidate(cache_key);
+        Self::get_shared_data_cache().invalidate(cache_key);
+    }
+
+    fn invalidate_partition(partition_id: &str) {
+        let token_cache = Self::get_token_cache();
+        for (cache_key, _) in token_cache.iter() {
+            if cache_key.0 == partition_id { token_cache.invalidate(&*cache_key); }
+        }
+
+        let lazy_token_cache = Self::get_lazy_token_cache();
+        for (cache_key, _) in lazy_token_cache.iter() {
+            if cache_key.0 == partition_id { lazy_token_cache.invalidate(&*cache_key); }
+        }
+
+        let data_cache = Self::get_data_cache();
+        for (cache_key, _) in data_cache.iter() {
+            if cache_key.0 == partition_id { data_cache.invalidate(&*cache_key); }
+        }
+
+        let shared_data_cache = Self::get_shared_data_cache();
+        for (cache_key, _) in shared_data_cache.iter() {
+            if cache_key.0 == partition_id { shared_data_cache.invalidate(&*cache_key); }
+        }
+    }
+
+    fn get_token_cache() -> &'static TokenCache {
+        TOKEN_CACHE.get_or_init(|| get_token_cache(Self::get_config()))
+    }
+
+    fn get_lazy_token_cache() -> &'static LazyTokenCache {
+        LAZY_TOKEN_CACHE.get_or_init(|| get_lazy_token_cache(Self::get_config()))
+    }
+
+    fn get_data_cache() -> &'static DataCache {
+        DATA_CACHE.get_or_init(|| get_data_cache(Self::get_config()))
+    }
+
+    fn get_shared_data_cache() -> &'static SharedDataCache {
+        SHARED_DATA_CACHE.get_or_init(|| get_shared_data_cache(Self::get_config()))
+    }
+
+    fn get_db_factory() -> &'static Box<dyn DbFactory> {
+        DB_FACTORY.get_or_init(|| get_db_factory(Self::get_config()))
+    }
+
+    fn get_db_handles() -> &'static HandleCache {
+        DB_HANDLES.get_or_init(|| get_db_handles(Self::get_config()))
+    }
+
+    fn get_config() -> &'static DataConfig {
+        CONFIG.get_or_init(|| DataConfig::default())
+    }
+}
+
+/// Block cache sizes in bytes. Pinned blocks are in use by readers and can't be evicted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BlockCacheUsage {
+    pub usage: u64,
+    pub pinned_usage: u64,
+    pub capacity: u64
+}
+
+/// What bulk_load_with does with a key that appears more than once in its input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DuplicateKeys {
+    /// The last value given is loaded.
+    LastWins,
+    /// The last value given is loaded, and the key is listed in the report.
+    Report,
+    /// The load fails with DataError::DuplicateKeys.
+    Reject
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BulkLoadReport {
+    pub keys_loaded: u64,
+    /// Keys given more than once, in key order. Only filled in under DuplicateKeys::Report.
+    pub duplicate_keys: Vec<Vec<u8>>
+}
+
+/// What save_typed_batch does with items that fail to serialize.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SerializeFailures {
+    /// Nothing is written and the first failure is returned.
+    Abort,
+    /// The other items are written and the failures reported.
+    Skip
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TypedBatchReport {
+    pub items_written: u64,
+    /// Keys of the items that didn't serialize, with why, in input order.
+    pub failures: Vec<(Vec<u8>, DataError)>
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CopyReport {
+    pub keys_copied: u64,
+    pub mismatches: Vec<Vec<u8>>
+}
+
+//////////////////// Globals ///////////////////////
+
+static TOKEN_CACHE: OnceLock<TokenCache> = OnceLock::new();
+static DATA_CACHE: OnceLock<DataCache> = OnceLock::new();
+static SHARED_DATA_CACHE: OnceLock<SharedDataCache> = OnceLock::new();
+static LAZY_TOKEN_CACHE: OnceLock<LazyTokenCache> = OnceLock::new();
+static DB_FACTORY: OnceLock<Box<dyn DbFactory>> = OnceLock::new();
+static DB_HANDLES: OnceLock<HandleCache> = OnceLock::new();
+static CONFIG: OnceLock<DataConfig> = OnceLock::new();
+static OPEN_LOCK: Mutex<()> = Mutex::new(());
+static VALUE_POOL: OnceLock<ValuePool> = OnceLock::new();
+static NEXT_BULK_LOAD: AtomicU64 = AtomicU64::new(0);
+static BLOCK_CACHE: OnceLock<rocksdb::Cache> = OnceLock::new();
+// handles evicted for space, for as long as anything still holds them (see get_db)
+static EVICTED_HANDLES: OnceLock<Mutex<HashMap<String, Weak<dyn Db>>>> = OnceLock::new();
+// partitions reopened while paused have to come back paused
+static COMPACTION_PAUSED: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+// the lag each secondary had when replica_lag last measured it
+static REPLICA_LAGS: OnceLock<RwLock<HashMap<String, u64>>> = OnceLock::new();
+thread_local! {
+    // how many observe calls the current thread is inside of
+    static OBSERVE_DEPTH: Cell<u32> = const { Cell::new(0) };
+}
+const CACHE_TIME_TO_IDLE: Duration = Duration::from_secs(30);
+// roughly what moka and the Arc keep per entry on top of its key and value
+const CACHE_ENTRY_OVERHEAD: usize = 96;
+
+// Leaves the observe call when dropped, so a panicking call doesn't leave the depth raised.
+struct ObservedCall {
+    outermost: bool
+}
+
+impl ObservedCall {
+    fn enter() -> Self {
+        let depth = OBSERVE_DEPTH.with(|depth| depth.replace(depth.get() + 1));
+        ObservedCall { outermost: depth == 0 }
+    }
+}
+
+impl Drop for ObservedCall {
+    fn drop(&mut self) {
+        OBSERVE_DEPTH.with(|depth| depth.set(depth.get() - 1));
+    }
+}
+
+fn compaction_paused() -> &'static Mutex<HashSet<String>> {
+    COMPACTION_PAUSED.get_or_init(|| Mutex::new(HashSet::new()))
+}
+
+fn evicted_handles() -> &'static Mutex<HashMap<String, Weak<dyn Db>>> {
+    EVICTED_HANDLES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn replica_lags() -> &'static RwLock<HashMap<String, u64>> {
+    REPLICA_LAGS.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+fn is_compaction_paused(partition_id: &str) -> bool {
+    compaction_paused().lock().unwrap_or_else(|poisoned| poisoned.into_inner()).contains(partition_id)
+}
+
+// Caches are shared by all partitions, so every entry is keyed by its partition as well
+fn cache_key(key: &Vec<u8>, partition_id: &str) -> CacheKey {
+    (partition_id.to_string(), key.clone())
+}
+
+// The smallest key greater than every key starting with `prefix`, or None if there's no such
+// key (the prefix is empty or all 0xff) and a prefix scan has to run to the end.
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+// A document's manifest key, which every one of its field keys extends.
+fn document_prefix(doc_key: &Vec<u8>) -> Vec<u8> {
+    let mut prefix = doc_key.clone();
+    prefix.push(0);
+    prefix
+}
+
+fn document_field_key(doc_key: &Vec<u8>, field: &str) -> Vec<u8> {
+    let mut key = document_prefix(doc_key);
+    key.extend_from_slice(field.as_bytes());
+    key
+}
+
+fn get_token_cache(config: &DataConfig) -> TokenCache {
+    // TODO: replace this with config.json call or something
+    let mut builder = Cache::builder()
+        .time_to_idle(CACHE_TIME_TO_IDLE);
+    match cache_budget_share(config) {
+        Some(bytes) => builder = builder.max_capacity(bytes)
+            .weigher(|cache_key, _token| entry_weight(cache_key, std::mem::size_of::<Token>())),
+        None => if let Some(capacity) = config.cache_capacity { builder = builder.max_capacity(capacity); }
+    }
+    builder.build()
+}
+
+fn get_lazy_token_cache(config: &DataConfig) -> LazyTokenCache {
+    let mut builder = Cache::builder()
+        .time_to_idle(CACHE_TIME_TO_IDLE);
+    match cache_budget_share(config) {
+        Some(bytes) => builder = builder.max_capacity(bytes)
+            .weigher(|cache_key, lazy_token: &Arc<LazyToken>| {
+                entry_weight(cache_key, lazy_token.serialized_len() + std::mem::size_of::<Token>())
+            }),
+        None => if let Some(capacity) = config.cache_capacity { builder = builder.max_capacity(capacity); }
+    }
+    builder.build()
+}
+
+fn get_data_cache(config: &DataConfig) -> DataCache {
+    // TODO: replace this with config.json call or something
+    let mut builder = Cache::builder()
+        .time_to_idle(CACHE_TIME_TO_IDLE);
+    match cache_budget_share(config) {
+        Some(bytes) => builder = builder.max_capacity(bytes)
+            .weigher(|cache_key, data: &Arc<RwLock<Vec<u8>>>| {
+                entry_weight(cache_key, data.read().map_or(0, |data| data.len()))
+            }),
+        None => if let Some(capacity) = config.cache_capacity { builder = builder.max_capacity(capacity); }
+    }
+    builder.build()
+}
+
+fn get_shared_data_cache(config: &DataConfig) -> SharedDataCache {
+    let mut builder = Cache::builder()
+        .time_to_idle(CACHE_TIME_TO_IDLE);
+    match cache_budget_share(config) {
+        Some(bytes) => builder = builder.max_capacity(bytes)
+            .weigher(|cache_key, data: &Arc<Vec<u8>>| entry_weight(cache_key, data.len())),
+        None => if let Some(capacity) = config.cache_capacity { builder = builder.max_capacity(capacity); }
+    }
+    builder.build()
+}
+
+// The budget goes half to tokens and half to data. Only one of the two data caches is ever
+// filled (immutable_data_cache picks which), so each can have the whole data half; the two
+// token caches each get the token half, most deployments only using one of them.
+fn cache_budget_share(config: &DataConfig) -> Option<u64> {
+    config.cache_memory_budget_mb.map(|mb| mb * 1024 * 1024 / 2)
+}
+
+fn entry_weight(cache_key: &CacheKey, value_size: usize) -> u32 {
+    let weight = cache_key.0.len() + cache_key.1.len() + value_size + CACHE_ENTRY_OVERHEAD;
+    weight.min(u32::MAX as usize) as u32
+}
+
+fn get_db_factory(config: &DataConfig) -> Box<dyn DbFactory> {
+    // TODO: replace this with config.json call or something (per partition_id?)
+    // TODO: use a dashmap to map env_ids to DbFactory instances
+    Box::new(RocksDbFactory { config: config.clone() })
+}
+
+fn get_db_handles(config: &DataConfig) -> HandleCache {
+    // A handle evicted while another thread still holds it stays open until that thread is
+    // done with it, so the limit is a soft one under heavy concurrent access. Until then it's
+    // kept as a Weak, for get_db to take back instead of failing to reopen the partition.
+    Cache::builder()
+        .max_capacity(config.max_open_partitions)
+        .eviction_policy(EvictionPolicy::lru())
+        .eviction_listener(|partition_id: Arc<String>, db: Arc<dyn Db>, cause| {
+            let _ = db.flush();
+            if cause != RemovalCause::Size { return; }
+            let mut evicted = evicted_handles().lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            evicted.retain(|_, db| db.strong_count() > 0);
+            evicted.insert(partition_id.to_string(), Arc::downgrade(&db));
+        })
+        .build()
+}
+
+////////////// Data Factories/Stores ////////////////
+
+trait Db : Send + Sync {
+    fn get_token(&self, key: &Vec<u8>) -> Result<Token, DataError>;
+    fn save_token(&self, key: &Vec<u8>, token: &Arc<RwLock<Token>>) -> Result<(), DataError>;
+    fn get_data(&self, key: &Vec<u8>) -> Result<Vec<u8>, DataError>;
+    fn save_data(&self, key: &Vec<u8>, data: &[u8]) -> Result<(), DataError>;
+    /// The value under `key` exactly as stored: not decoded, and returned even if expired.
+    fn get_stored(&self, key: &Vec<u8>) -> Result<Vec<u8>, DataError>;
+    /// Writes each value exactly as given, in one batch, e.g. values read with scan_stored.
+    /// They must already be in this backend's encoding.
+    fn put_stored(&self, entries: &[(Vec<u8>, Vec<u8>)]) -> Result<(), DataError>;
+    /// Like get_data, also returning the value's expiry. Unlike get_data, which only hides
+    /// expired values, this deletes them, so callers must hold the key's lock.
+    fn get_data_with_expiry(&self, key: &Vec<u8>) -> Result<(Vec<u8>, Option<SystemTime>), DataError> {
+        Ok((self.get_data(key)?, None))
+    }
+    /// Saves a value compressed as `hint` says. Backends that never compress ignore the hint.
+    fn save_data_hinted(&self, key: &Vec<u8>, data: &[u8], _hint: CompressionHint) -> Result<(), DataError> {
+        self.save_data(key, data)
+    }
+    /// Saves a value with `type_tag` recorded alongside it.
+    fn save_data_tagged(&self, _key: &Vec<u8>, _data: &[u8], _type_tag: &str) -> Result<(), DataError> {
+        Err(DataError::FromStore("this backend doesn't support type tags".to_string()))
+    }
+    /// The type tag the value under `key` was saved with, if any.
+    fn type_tag(&self, key: &Vec<u8>) -> Result<Option<String>, DataError> {
+        self.get_data(key).map(|_| None)
+    }
+    /// Saves a value that reads as absent from `expires_at` on.
+    fn save_data_until(&self, _key: &Vec<u8>, _data: &[u8], _expires_at: SystemTime) -> Result<(), DataError> {
+        Err(DataError::FromStore("this backend doesn't support expiring values".to_string()))
+    }
+    /// Appends `operand` to the value under `key`.
+    fn merge(&self, key: &Vec<u8>, operand: &[u8]) -> Result<(), DataError>;
+    /// Adds one to the counter under `key` (starting from 0), durably, and returns the new
+    /// count. Callers must hold the key's lock, or two of them could read the same count.
+    fn increment(&self, key: &Vec<u8>) -> Result<u64, DataError>;
+    /// Adds `delta` to the refcount under `key` (starting from 0) and returns the new count.
+    /// Callers must hold the key's lock.
+    fn adjust_refcount(&self, key: &Vec<u8>, delta: i64) -> Result<i64, DataError>;
+    /// Writes every memtable out, column families included.
+    fn flush(&self) -> Result<(), DataError>;
+    /// Syncs the write-ahead log to disk, making every write so far durable.
+    fn sync_wal(&self) -> Result<(), DataError>;
+    /// Visits every entry with `start <= key < end` in key order until `visit` returns false.
+    fn scan(&self, start: Option<&[u8]>, end: Option<&[u8]>,
+            visit: &mut dyn FnMut(&[u8], &[u8]) -> bool) -> Result<(), DataError>;
+    /// Like scan, but values are handed out as stored, without decoding them.
+    fn scan_stored(&self, start: Option<&[u8]>, end: Option<&[u8]>,
+                   visit: &mut dyn FnMut(&[u8], &[u8]) -> bool) -> Result<(), DataError>;
+    /// Like scan, for callers that only need the keys.
+    fn scan_keys(&self, start: Option<&[u8]>, end: Option<&[u8]>,
+                 visit: &mut dyn FnMut(&[u8]) -> bool) -> Result<(), DataError>;
+    /// Reads each key's value, None for a key without one.
+    fn multi_get(&self, keys: &[&Vec<u8>]) -> Vec<Result<Option<Vec<u8>>, DataError>> {
+        keys.iter().map(|key| match self.get_data(key) {
+            Err(DataError::DataNotFound) => Ok(None),
+            Err(err) => Err(err),
+            Ok(data) => Ok(Some(data))
+        }).collect()
+    }
+    /// The first and last keys, None if there are none. Backends that can seek to either end
+    /// should do that instead of this scan.
+    fn key_bounds(&self) -> Result<Option<(Vec<u8>, Vec<u8>)>, DataError> {
+        let mut bounds: Option<(Vec<u8>, Vec<u8>)> = None;
+        self.scan_keys(None, None, &mut |key| {
+            match bounds.as_mut() {
+                None => bounds = Some((key.to_vec(), key.to_vec())),
+                Some((_, last)) => *last = key.to_vec()
+            }
+            true
+        })?;
+        Ok(bounds)
+    }
+    /// Applies every op atomically.
+    fn write_batch(&self, ops: &[BatchOp]) -> Result<(), DataError>;
+    /// Writes each shard (sorted, unique keys, not overlapping the other shards) and makes
+    /// them all visible at once.
+    fn ingest(&self, shards: &[&[(Vec<u8>, Vec<u8>)]]) -> Result<(), DataError>;
+    fn write_amplification(&self) -> Result<f64, DataError>;
+    fn block_cache_usage(&self) -> Result<BlockCacheUsage, DataError>;
+    /// Bytes the backend takes up on disk.
+    fn disk_size(&self) -> Result<u64, DataError>;
+    /// Compacts everything down as far as it goes, returning once compaction is done.
+    fn compact(&self) -> Result<(), DataError>;
+    /// Writes a consistent copy of the backend's durable data to `dest`, which mustn't exist.
+    fn checkpoint(&self, dest: &Path) -> Result<(), DataError>;
+    /// Turns the backend's background compactions on or off. Backends that don't compact
+    /// ignore it.
+    fn set_auto_compaction(&self, _enabled: bool) -> Result<(), DataError> {
+        Ok(())
+    }
+    /// Visits the writes still in the WAL from sequence number `since` on, oldest first, until
+    /// `visit` returns false.
+    fn changes_since(&self, _since: u64, _visit: &mut dyn FnMut(ChangeRecord) -> bool) -> Result<(), DataError> {
+        Err(DataError::FromStore("this backend keeps no change log".to_string()))
+    }
+    /// Catches a secondary instance up with its primary. False (and nothing done) for a primary.
+    fn catch_up(&self) -> Result<bool, DataError>;
+    /// How many sequence numbers a secondary is behind its primary, 0 for a primary.
+    fn replica_lag(&self) -> Result<u64, DataError> {
+        Ok(0)
+    }
+    /// Reads every entry, verifying checksums, and returns the number of entries.
+    fn verify(&self) -> Result<u64, DataError>;
+    /// Stored size of a value. Backends that track sizes should answer without reading it.
+    fn value_size(&self, key: &Vec<u8>) -> Result<Option<usize>, DataError> {
+        match self.get_data(key) {
+            Err(DataError::DataNotFound) => Ok(None),
+            Err(err) => Err(err),
+            Ok(data) => Ok(Some(data.len()))
+        }
+    }
+    /// Fails the way any write would right now (e.g. on a secondary), for writes going around
+    /// this backend.
+    fn writable(&self) -> Result<(), DataError> {
+        Ok(())
+    }
+    /// Whether saves over existing values and deletes are refused (see PartitionConfig).
+    fn append_only(&self) -> bool {
+        false
+    }
+
+    /// Namespaced variants of get_data/save_data/scan, each namespace being its own keyspace.
+    fn get_in(&self, namespace: &str, key: &Vec<u8>) -> Result<Vec<u8>, DataError>;
+    fn save_in(&self, namespace: &str, key: &Vec<u8>, data: &[u8]) -> Result<(), DataError>;
+    fn scan_in(&self, namespace: &str, start: Option<&[u8]>, end: Option<&[u8]>,
+               visit: &mut dyn FnMut(&[u8], &[u8]) -> bool) -> Result<(), DataError>;
+}
+
+trait DbFactory : Send + Sync {
+    fn get_db(&self, partition_id: &str) -> Result<Arc<dyn Db>, DataError>;
+    /// Like get_db, but fails if the partition already exists.
+    fn create_db(&self, partition_id: &str) -> Result<Arc<dyn Db>, DataError>;
+    /// Deletes the partition's data. Any handle to it must already be closed.
+    fn destroy_db(&self, partition_id: &str) -> Result<(), DataError>;
+}
+
+enum BatchOp {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>)
+}
+
+struct RocksDbFactory {
+    config: DataConfig
+}
+
+impl DbFactory for RocksDbFactory {
+    fn get_db(&self, partition_id: &str) -> Result<Arc<dyn Db>, DataError> {
+        let db = RocksDb::new(partition_id, &self.config)?;
+        Ok(Self::routed(partition_id, Arc::new(db)))
+    }
+
+    fn create_db(&self, partition_id: &str) -> Result<Arc<dyn Db>, DataError> {
+        let db = RocksDb::create(partition_id, &self.config)?;
+        Ok(Self::routed(partition_id, Arc::new(db)))
+    }
+
+    fn destroy_db(&self, partition_id: &str) -> Result<(), DataError> {
+        memory::discard(partition_id);
+        let opts = RocksDb::with_options(&self.config, &self.config.partition(partition_id));
+        match DBWithThreadMode::<MultiThreaded>::destroy(&opts, partition_id) {
+            Err(err) => Err(DataError::FromStore(err.into_string())),
+            Ok(_) => Ok(())
+        }
+    }
+}
+
+impl RocksDbFactory {
+    fn routed(partition_id: &str, db: Arc<dyn Db>) -> Arc<dyn Db> {
+        match hooks::router(partition_id) {
+            None => db,
+            Some(router) => Arc::new(RoutedDb::new(db, memory::for_partition(partition_id), router))
+        }
+    }
+}
+
+struct RocksDb {
+    store: DBWithThreadMode<MultiThreaded>,
+    opts: Options,
+    codec: ValueCodec,
+    secondary: bool,
+    // a second secondary of the same primary, caught up to measure how far behind this one is
+    lag_probe_path: Option<PathBuf>,
+    lag_probe: Mutex<Option<DBWithThreadMode<MultiThreaded>>>,
+    canonical_tokens: bool,
+    read_only_on_out_of_space: bool,
+    verify_writes: bool,
+    append_only: bool,
+    // set once a write has run out of space, with read_only_on_out_of_space on
+    out_of_space: AtomicBool
+}
+
+impl RocksDb {
+    fn new(partition_id: &str, config: &DataConfig) -> Result<Self, DataError> {
+        let partition = config.partition(partition_id);
+        let codec = ValueCodec::new(&partition)?;
+        let mut opts = Self::with_options(config, &partition);
+        if is_compaction_paused(partition_id) { opts.set_disable_auto_compactions(true); }
+        Self::set_compaction_filter(&mut opts, partition_id, &partition)?;
+        Self::set_merge_operator(&mut opts, &partition)?;
+        if partition.secondary_path.is_some() { opts.set_max_open_files(-1); }
+        let mut db = Self::open(partition_id, opts, codec, partition.secondary_path.as_deref())?;
+        db.lag_probe_path = partition.secondary_path.as_ref().map(|path| Path::new(path).join("lag_probe"));
+        db.canonical_tokens = partition.canonical_tokens;
+        db.read_only_on_out_of_space = config.read_only_on_out_of_space;
+        db.verify_writes = config.verify_writes;
+        db.append_only = partition.append_only;
+        if db.codec.is_encrypting() { db.check_encryption_key()?; }
+        if config.verify_on_open { db.verify()?; }
+        Ok(db)
+    }
+
+    fn create(partition_id: &str, config: &DataConfig) -> Result<Self, DataError> {
+        let partition = config.partition(partition_id);
+        if partition.secondary_path.is_some() { return Err(DataError::ReadOnly); }
+
+        let codec = ValueCodec::new(&partition)?;
+        let mut opts = Self::with_options(config, &partition);
+        if is_compaction_paused(partition_id) { opts.set_disable_auto_compactions(true); }
+        Self::set_compaction_filter(&mut opts, partition_id, &partition)?;
+        Self::set_merge_operator(&mut opts, &partition)?;
+        opts.set_error_if_exists(true);
+        let mut db = Self::open(partition_id, opts, codec, None)?;
+        db.canonical_tokens = partition.canonical_tokens;
+        db.read_only_on_out_of_space = config.read_only_on_out_of_space;
+        db.verify_writes = config.verify_writes;
+        db.append_only = partition.append_only;
+        Ok(db)
+    }
+
+    fn open(partition_id: &str, opts: Options, codec: ValueCodec, secondary_path: Option<&str>)
+            -> Result<Self, DataError> {
+        // every existing column family has to be opened along with the db; listing fails
+        // when the partition doesn't exist yet, in which case there are none
+        let column_families = DBWithThreadMode::<MultiThreaded>::list_cf(&opts, partition_id)
+            .unwrap_or_default();
+        let opened = match secondary_path {
+            None => DBWithThreadMode::open_cf(&opts, partition_id, column_families),
+            Some(secondary_path) =>
+                DBWithThreadMode::open_cf_as_secondary(&opts, partition_id, secondary_path, column_families)
+        };
+
+        match opened {
+            Err(err) => Err(err.into()),
+            Ok(db) => {
+                let rocks_db = RocksDb {
+                    store: db,
+                    opts,
+                    codec,
+                    secondary: secondary_path.is_some(),
+                    lag_probe_path: None,
+                    lag_probe: Mutex::new(None),
+                    canonical_tokens: false,
+                    read_only_on_out_of_space: false,
+                    verify_writes: false,
+                    append_only: false,
+                    out_of_space: AtomicBool::new(false)
+                };
+                Ok(rocks_db)
+            }
+        }
+    }
+
+    fn check_writable(&self) -> Result<(), DataError> {
+        if self.out_of_space.load(Ordering::Relaxed) { return Err(DataError::OutOfSpace); }
+        match self.secondary {
+            true => Err(DataError::ReadOnly),
+            false => Ok(())
+        }
+    }
+
+    // Single-key saves all land here. With verify_writes the value is read straight back from
+    // the store, never the cache, and has to be the bytes written and decode back to `data`.
+    fn put(&self, key: &Vec<u8>, data: &[u8], stored: Vec<u8>) -> Result<(), DataError> {
+        Self::check_encodable(key)?;
+        self.check_append(key)?;
+        stats::record(Stat::DbWrite);
+        let expected = self.verify_writes.then(|| stored.clone());
+        if let Err(err) = self.store.put(key, stored) { return Err(self.write_failed(err.into())); }
+        let Some(expected) = expected
+            else { return Ok(()) };
+
+        stats::record(Stat::DbRead);
+        match self.store.get(key) {
+            Err(err) => Err(err.into()),
+            Ok(Some(read_back)) if read_back == expected
+                && self.codec.decode_owned(read_back).is_ok_and(|decoded| decoded == data) => Ok(()),
+            Ok(_) => Err(DataError::WriteVerificationFailed)
+        }
+    }
+
+    // Nothing encoded may land on a counter or refcount key, whose merges would then fail to
+    // parse it; those only change through increment and adjust_refcount.
+    fn check_encodable(key: &[u8]) -> Result<(), DataError> {
+        match merge::is_raw(key) {
+            true => Err(DataError::OperationNotAllowed),
+            false => Ok(())
+        }
+    }
+
+    // The provider's own bookkeeping (rings, refcounts and the like) is rewritten as usual.
+    fn check_append(&self, key: &[u8]) -> Result<(), DataError> {
+        if !self.append_only || merge::is_reserved(key) { return Ok(()); }
+        stats::record(Stat::DbRead);
+        match self.store.get_pinned(key) {
+            Err(err) => Err(err.into()),
+            Ok(Some(_)) => Err(DataError::AlreadyExists),
+            Ok(None) => Ok(())
+        }
+    }
+
+    fn write_failed(&self, err: DataError) -> DataError {
+        if err == DataError::OutOfSpace && self.read_only_on_out_of_space {
+            self.out_of_space.store(true, Ordering::Relaxed);
+        }
+        err
+    }
+
+    fn with_options(config: &DataConfig, partition: &PartitionConfig) -> Options {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        if let Some(ttl) = config.wal_ttl_seconds { opts.set_wal_ttl_seconds(ttl); }
+        if let Some(limit) = config.wal_size_limit_mb { opts.set_wal_size_limit_mb(limit); }
+        if let Some(num) = config.recycle_log_file_num { opts.set_recycle_log_file_num(num); }
+        if config.enable_statistics { opts.enable_statistics(); }
+        opts.set_paranoid_checks(config.paranoid_checks);
+        opts.set_max_open_files(partition.max_open_files.unwrap_or(config.max_open_files));
+        opts.set_allow_mmap_reads(partition.allow_mmap_reads);
+        if config.block_cache_mb.is_some() || partition.block_size.is_some() {
+            let mut block_opts = BlockBasedOptions::default();
+            if let Some(block_cache_mb) = config.block_cache_mb {
+                block_opts.set_block_cache(BLOCK_CACHE.get_or_init(|| rocksdb::Cache::new_lru_cache(block_cache_mb << 20)));
+            }
+            if let Some(block_size) = partition.block_size { block_opts.set_block_size(block_size); }
+            opts.set_block_based_table_factory(&block_opts);
+        }
+        opts
+    }
+
+    fn set_compaction_filter(opts: &mut Options, partition_id: &str, partition: &PartitionConfig)
+                             -> Result<(), DataError> {
+        let Some(filter) = hooks::compaction_filter(partition_id)
+            else { return Ok(()) };
+
+        // the filter gets its own codec since it outlives any borrow of the db's
+        let codec = ValueCodec::new(partition)?;
+        opts.set_compaction_filter("pneumatic_data", move |_level, key, stored| {
+            if merge::is_reserved(key) { return Decision::Keep; }
+            match codec.decode(stored) {
+                Err(_) => Decision::Keep,
+                Ok(value) => match filter(key, &value) {
+                    CompactionDecision::Keep => Decision::Keep,
+                    CompactionDecision::Remove => Decision::Remove
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn set_merge_operator(opts: &mut Options, partition: &PartitionConfig) -> Result<(), DataError> {
+        let codec = Arc::new(ValueCodec::new(partition)?);
+        opts.set_merge_operator_associative("pneumatic_data", move |key, existing, operands| {
+            merge::merge(&codec, key, existing, operands)
+        });
+        Ok(())
+    }
+
+    // Decrypting a sample of existing values is enough to tell a wrong key from the right one,
+    // without reading the whole partition on every open.
+    fn check_encryption_key(&self) -> Result<(), DataError> {
+        const SAMPLE_SIZE: usize = 64;
+
+        let mut sampled = 0;
+        let mut result = Ok(());
+        Self::visit_entries(self.store.iterator(IteratorMode::Start), &mut |_, value| {
+            if let Err(DataError::DecryptionFailed) = self.codec.decode(value) {
+                result = Err(DataError::DecryptionFailed);
+                return false;
+            }
+            sampled += 1;
+            sampled < SAMPLE_SIZE
+        })?;
+        result
+    }
+
+    fn iter_status(iter: &rocksdb::DBRawIteratorWithThreadMode<'_, DBWithThreadMode<MultiThreaded>>) -> Result<(), DataError> {
+        match iter.status() {
+            Err(err) => Err(DataError::FromStore(err.into_string())),
+            Ok(_) => Ok(())
+        }
+    }
 
-pub struct SafeDataProvider { }
+    fn column_family(&self, name: &str) -> Option<Arc<BoundColumnFamily<'_>>> {
+        self.store.cf_handle(name)
+    }
 
-//impl DataProvider for SafeDataProvider {}
+    fn create_column_family(&self, name: &str) -> Result<Arc<BoundColumnFamily<'_>>, DataError> {
+        if let Some(cf) = self.column_family(name) { return Ok(cf); }
 
-impl SafeDataProvider {
-    pub fn get_token(key: &Vec<u8>, partition_id: &str) -> Result<Arc<RwLock<Token>>, DataError> {
-        let cache = Self::get_token_cache();
-        if let Some(token_entry) = cache.get(key) { return Ok(token_entry.clone()); }
+        // a concurrent save may have just created it, so only a still-missing cf is an error
+        let created = self.store.create_cf(name, &self.opts);
+        match (self.column_family(name), created) {
+            (Some(cf), _) => Ok(cf),
+            (None, Err(err)) => Err(DataError::FromStore(err.into_string())),
+            (None, Ok(_)) => Err(DataError::FromStore(format!("column family {name} wasn't created")))
+        }
+    }
 
-        let token = Self::get_token_from_db(key, partition_id)?;
-        Self::put_in_token_cache(key, Arc::new(RwLock::new(token)));
-        cache.get(key).ok_or(DataError::CacheError)
+    fn write_sst(&self, entries: &[(Vec<u8>, Vec<u8>)], path: &Path) -> Result<(), DataError> {
+        let mut writer = SstFileWriter::create(&self.opts);
+        writer.open(path)?;
+        for (key, value) in entries { writer.put(key, self.codec.encode(value)?)?; }
+        writer.finish()?;
+        Ok(())
     }
 
-    pub fn save_token(key: &Vec<u8>, token_ref: Arc<RwLock<Token>>, partition_id: &str)
-                      -> Result<(), DataError> {
-        let db = Self::get_db_factory().get_db(partition_id)?;
-        let _ = db.save_token(key, &token_ref)?;
-        Self::put_in_token_cache(key, token_ref);
+    fn visit_entries(entries: impl Iterator<Item=Result<(Box<[u8]>, Box<[u8]>), rocksdb::Error>>,
+                     visit: &mut dyn FnMut(&[u8], &[u8]) -> bool) -> Result<(), DataError> {
+        for item in entries {
+            match item {
+                Err(err) => return Err(DataError::FromStore(err.into_string())),
+                Ok((key, value)) => if !visit(&key[..], &value[..]) { break; }
+            }
+        }
         Ok(())
     }
 
-    pub fn get_data(key: &Vec<u8>, partition_id: &str)
-                    -> Result<Arc<RwLock<Vec<u8>>>, DataError> {
-        let cache = Self::get_data_cache();
-        if let Some(data_entry) = cache.get(key) { return Ok(data_entry.clone()); }
+    fn visit_decoded(&self, entries: impl Iterator<Item=Result<(Box<[u8]>, Box<[u8]>), rocksdb::Error>>,
+                     visit: &mut dyn FnMut(&[u8], &[u8]) -> bool) -> Result<(), DataError> {
+        let mut decode_result = Ok(());
+        Self::visit_entries(entries, &mut |key, value| {
+            // counters and refcounts never went through the codec
+            if merge::is_raw(key) { return visit(key, value); }
+            if self.is_expired(value) { return true; }
+            match self.codec.decode(value) {
+                Err(err) => {
+                    decode_result = Err(err);
+                    false
+                },
+                Ok(value) => visit(key, &*value)
+            }
+        })?;
+        decode_result
+    }
 
-        let db = Self::get_db_factory().get_db(partition_id)?;
-        let data = db.get_data(key)?;
-        Self::put_in_data_cache(key, Arc::new(RwLock::new(data)));
-        cache.get(key).ok_or(DataError::CacheError)
+    // a value whose header doesn't parse isn't treated as expired, so decoding reports it
+    fn is_expired(&self, stored: &[u8]) -> bool {
+        let Ok(Some(expires_at)) = self.codec.expiry(stored)
+            else { return false };
+        expires_at <= SystemTime::now()
     }
 
-    pub fn save_data(key: &Vec<u8>, data: Vec<u8>, partition_id: &str) -> Result<(), DataError> {
-        let db = Self::get_db_factory().get_db(partition_id)?;
-        let _ = db.save_data(key, &data)?;
-        Self::put_in_data_cache(key, Arc::new(RwLock::new(data)));
-        Ok(())
+    fn scan_options<'a>(start: Option<&'a [u8]>, end: Option<&[u8]>) -> (IteratorMode<'a>, ReadOptions) {
+        let mode = match start {
+            Some(start) => IteratorMode::From(start, Direction::Forward),
+            None => IteratorMode::Start
+        };
+        let mut read_opts = ReadOptions::default();
+        if let Some(end) = end { read_opts.set_iterate_upper_bound(end.to_vec()); }
+        (mode, read_opts)
     }
+}
 
-    pub fn save_typed_data<T: Serialize>(key: &Vec<u8>, data: &T, partition_id: &str) -> Result<(), DataError> {
-        // let db = Self::get_db_factory().get_db(partition_id)?;
-        // let Ok(serialized) = serialize_to_bytes_rmp(data)
-            // .map(|serialized| db.save_data(key, &serialized))
-            // .unwrap_or_else(|err| Err(DataError::FromStore(err.to_string())))?;
-            // .map(|serialized| Self::put_in_data_cache(key, Arc::new(RwLock::new(serialized))))
-            //else { return Err(DataError::SerializationError) };
+impl Db for RocksDb {
+    fn get_token(&self, key: &Vec<u8>) -> Result<Token, DataError> {
+        let data = self.get_data(key)?;
+        match deserialize_rmp_to::<Token>(&data) {
+            Err(err) => Err(DataError::DeserializationError(err.to_string())),
+            Ok(token) => Ok(token)
+        }
+    }
 
-        // let _ = db.save_data(key, &serialized)?;
-        // Self::put_in_data_cache(key, Arc::new(RwLock::new(serialized)));
-        Ok(())
+    fn save_token(&self, key: &Vec<u8>, token_ref: &Arc<RwLock<Token>>) -> Result<(), DataError> {
+        let Ok(token) = token_ref.write()
+            else { return Err(DataError::Poisoned) };
+
+        if self.canonical_tokens { return self.save_data(key, &serialize_canonical(token.deref())?); }
+        match serialize_to_bytes_rmp(token.deref()) {
+            Ok(data) => self.save_data(key, &data),
+            Err(err) => Err(DataError::SerializationError(err.to_string()))
+        }
     }
 
-    pub fn save_locked_data<T: Serialize>(key: &Vec<u8>, data: Arc<RwLock<T>>, partition_id: &str)
-                                                 -> Result<(), DataError> {
-        // let db = Self::get_db_factory().get_db(partition_id)?;
-        // let Ok(write_data) = data.write()
-        //     else { return Err(DataError::Poisoned) };
-        // let Ok(serialized) = serialize_to_bytes_rmp(write_data.deref())
-        //     else { return Err(DataError::SerializationError) };
-        //
-        // let _ = db.save_data(key, &serialized)?;
-        // Self::put_in_data_cache(key, Arc::new(RwLock::new(serialized)));
-        Ok(())
+    fn get_data(&self, key: &Vec<u8>) -> Result<Vec<u8>, DataError> {
+        stats::record(Stat::DbRead);
+        match self.store.get(key) {
+            Err(e) => Err(DataError::FromStore(e.into_string())),
+            Ok(None) => Err(DataError::DataNotFound),
+            Ok(Some(data)) if self.is_expired(&data) => Err(DataError::DataNotFound),
+            Ok(Some(data)) => self.codec.decode_owned(data)
+        }
     }
 
-    fn get_token_from_db(key: &Vec<u8>, partition_id: &str) -> Result<Token, DataError> {
-        let db = Self::get_db_factory().get_db(partition_id)?;
-        db.get_token(key)
+    fn get_stored(&self, key: &Vec<u8>) -> Result<Vec<u8>, DataError> {
+        stats::record(Stat::DbRead);
+        match self.store.get(key) {
+            Err(err) => Err(err.into()),
+            Ok(None) => Err(DataError::DataNotFound),
+            Ok(Some(stored)) => Ok(stored)
+        }
+    }
+
+    fn put_stored(&self, entries: &[(Vec<u8>, Vec<u8>)]) -> Result<(), DataError> {
+        self.check_writable()?;
+        let mut batch = WriteBatch::default();
+        for (key, stored) in entries {
+            self.check_append(key)?;
+            batch.put(key, stored);
+        }
+
+        stats::record(Stat::DbWrite);
+        match self.store.write(batch) {
+            Err(err) => Err(self.write_failed(err.into())),
+            Ok(_) => Ok(())
+        }
     }
 
-    fn put_in_token_cache(key: &Vec<u8>, data: Arc<RwLock<Token>>) {
-        Self::get_token_cache().insert(key.clone(), data)
+    fn get_data_with_expiry(&self, key: &Vec<u8>) -> Result<(Vec<u8>, Option<SystemTime>), DataError> {
+        stats::record(Stat::DbRead);
+        let stored = match self.store.get(key) {
+            Err(e) => return Err(DataError::FromStore(e.into_string())),
+            Ok(None) => return Err(DataError::DataNotFound),
+            Ok(Some(stored)) => stored
+        };
+
+        let expires_at = self.codec.expiry(&stored)?;
+        if expires_at.is_some_and(|expires_at| expires_at <= SystemTime::now()) {
+            // a partition that can't take writes leaves it for later, the read still succeeds;
+            // an append-only one keeps it for good, just hidden
+            if self.check_writable().is_ok() && !self.append_only {
+                stats::record(Stat::DbWrite);
+                if let Err(err) = self.store.delete(key) { return Err(self.write_failed(err.into())); }
+            }
+            return Err(DataError::DataNotFound);
+        }
+        Ok((self.codec.decode_owned(stored)?, expires_at))
     }
 
-    fn put_in_data_cache(key: &Vec<u8>, data: Arc<RwLock<Vec<u8>>>) {
-        Self::get_data_cache().insert(key.clone(), data)
+    fn save_data_hinted(&self, key: &Vec<u8>, data: &[u8], hint: CompressionHint) -> Result<(), DataError> {
+        self.check_writable()?;
+        let stored = self.codec.encode_hinted(data, hint)?;
+        self.put(key, data, stored)
     }
 
-    fn get_token_cache() -> &'static TokenCache {
-        TOKEN_CACHE.get_or_init(|| get_token_cache())
+    fn save_data_tagged(&self, key: &Vec<u8>, data: &[u8], type_tag: &str) -> Result<(), DataError> {
+        self.check_writable()?;
+        let stored = self.codec.encode_tagged(data, type_tag)?;
+        self.put(key, data, stored)
     }
 
-    fn get_data_cache() -> &'static DataCache {
-        DATA_CACHE.get_or_init(|| get_data_cache())
+    fn type_tag(&self, key: &Vec<u8>) -> Result<Option<String>, DataError> {
+        // only the header is needed, so the value is neither copied nor decoded
+        stats::record(Stat::DbRead);
+        match self.store.get_pinned(key) {
+            Err(e) => Err(DataError::FromStore(e.into_string())),
+            Ok(None) => Err(DataError::DataNotFound),
+            Ok(Some(stored)) if self.is_expired(&stored) => Err(DataError::DataNotFound),
+            Ok(Some(stored)) => self.codec.type_tag(&stored)
+        }
     }
 
-    fn get_db_factory() -> &'static Box<dyn DbFactory> {
-        DB_FACTORY.get_or_init(|| get_db_factory())
+    fn save_data_until(&self, key: &Vec<u8>, data: &[u8], expires_at: SystemTime) -> Result<(), DataError> {
+        self.check_writable()?;
+        let stored = self.codec.encode_until(data, Some(expires_at))?;
+        self.put(key, data, stored)
     }
-}
 
-//////////////////// Globals ///////////////////////
+    fn save_data(&self, key: &Vec<u8>, data: &[u8]) -> Result<(), DataError> {
+        self.check_writable()?;
+        let stored = self.codec.encode(data)?;
+        self.put(key, data, stored)
+    }
 
-static TOKEN_CACHE: OnceLock<TokenCache> = OnceLock::new();
-static DATA_CACHE: OnceLock<DataCache> = OnceLock::new();
-static DB_FACTORY: OnceLock<Box<dyn DbFactory>> = OnceLock::new();
+    fn merge(&self, key: &Vec<u8>, operand: &[u8]) -> Result<(), DataError> {
+        self.check_writable()?;
+        Self::check_encodable(key)?;
+        let stored = self.codec.encode(operand)?;
+        stats::record(Stat::DbWrite);
+        match self.store.merge(key, stored) {
+            Err(err) => Err(self.write_failed(err.into())),
+            Ok(_) => Ok(())
+        }
+    }
 
-fn get_token_cache() -> TokenCache {
-    // TODO: replace this with config.json call or something
-    Cache::builder()
-        .time_to_idle(Duration::from_secs(30))
-        .build()
-}
+    fn increment(&self, key: &Vec<u8>) -> Result<u64, DataError> {
+        self.check_writable()?;
+        // synced, so a count handed out is never handed out again after a crash
+        let mut write_opts = WriteOptions::default();
+        write_opts.set_sync(true);
+        stats::record(Stat::DbWrite);
+        if let Err(err) = self.store.merge_opt(key, 1u64.to_le_bytes(), &write_opts) {
+            return Err(self.write_failed(err.into()));
+        }
 
-fn get_data_cache() -> DataCache {
-    // TODO: replace this with config.json call or something
-    Cache::builder()
-        .time_to_idle(Duration::from_secs(30))
-        .build()
-}
+        stats::record(Stat::DbRead);
+        match self.store.get(key) {
+            Err(err) => Err(DataError::FromStore(err.into_string())),
+            Ok(stored) => stored.as_deref()
+                .and_then(merge::counter_value)
+                .ok_or_else(|| DataError::DeserializationError("counter value is corrupt".to_string()))
+        }
+    }
 
-fn get_db_factory() -> Box<dyn DbFactory> {
-    // TODO: replace this with config.json call or something (per partition_id?)
-    // TODO: use a dashmap to map env_ids to DbFactory instances
-    Box::new(RocksDbFactory { })
-}
+    fn adjust_refcount(&self, key: &Vec<u8>, delta: i64) -> Result<i64, DataError> {
+        self.check_writable()?;
+        stats::record(Stat::DbWrite);
+        if let Err(err) = self.store.merge(key, delta.to_le_bytes()) {
+            return Err(self.write_failed(err.into()));
+        }
 
-////////////// Data Factories/Stores ////////////////
+        stats::record(Stat::DbRead);
+        match self.store.get(key) {
+            Err(err) => Err(DataError::FromStore(err.into_string())),
+            Ok(stored) => stored.as_deref()
+                .and_then(merge::refcount_value)
+                .ok_or_else(|| DataError::DeserializationError("refcount value is corrupt".to_string()))
+        }
+    }
 
-trait Db {
-    fn get_token(&self, key: &Vec<u8>) -> Result<Token, DataError>;
-    fn save_token(&self, key: &Vec<u8>, token: &Arc<RwLock<Token>>) -> Result<(), DataError>;
-    fn get_data(&self, key: &Vec<u8>) -> Result<Vec<u8>, DataError>;
-    fn save_data(&self, key: &Vec<u8>, data: &Vec<u8>) -> Result<(), DataError>;
-}
+    fn flush(&self) -> Result<(), DataError> {
+        // a secondary has no memtable of its own to flush
+        if self.secondary { return Ok(()); }
+        if let Err(err) = self.store.flush() { return Err(self.write_failed(err.into())); }
 
-trait DbFactory : Send + Sync {
-    fn get_db(&self, partition_id: &str) -> Result<Box<dyn Db>, DataError>;
-}
+        // namespaced and contract data live in column families of their own
+        let column_families = DBWithThreadMode::<MultiThreaded>::list_cf(&self.opts, self.store.path())
+            .unwrap_or_default();
+        for name in column_families {
+            let Some(cf) = self.column_family(&name)
+                else { continue };
+            if let Err(err) = self.store.flush_cf(&cf) { return Err(self.write_failed(err.into())); }
+        }
+        Ok(())
+    }
 
-struct RocksDbFactory { }
+    fn sync_wal(&self) -> Result<(), DataError> {
+        if self.secondary { return Ok(()); }
+        match self.store.flush_wal(true) {
+            Err(err) => Err(self.write_failed(err.into())),
+            Ok(_) => Ok(())
+        }
+    }
 
-impl DbFactory for RocksDbFactory {
-    fn get_db(&self, partition_id: &str) -> Result<Box<dyn Db>, DataError> {
-        let db = RocksDb::new(partition_id)?;
-        Ok(Box::new(db))
+    fn scan(&self, start: Option<&[u8]>, end: Option<&[u8]>,
+            visit: &mut dyn FnMut(&[u8], &[u8]) -> bool) -> Result<(), DataError> {
+        let (mode, read_opts) = Self::scan_options(start, end);
+        stats::record(Stat::DbScan);
+        self.visit_decoded(self.store.iterator_opt(mode, read_opts), visit)
     }
-}
 
-struct RocksDb {
-    store: DBWithThreadMode<MultiThreaded>
-}
+    fn scan_stored(&self, start: Option<&[u8]>, end: Option<&[u8]>,
+                   visit: &mut dyn FnMut(&[u8], &[u8]) -> bool) -> Result<(), DataError> {
+        let (mode, read_opts) = Self::scan_options(start, end);
+        stats::record(Stat::DbScan);
+        Self::visit_entries(self.store.iterator_opt(mode, read_opts), visit)
+    }
 
-impl RocksDb {
-    fn new(partition_id: &str) -> Result<Self, DataError> {
-        match DBWithThreadMode::open(&Self::with_options(), partition_id) {
+    fn multi_get(&self, keys: &[&Vec<u8>]) -> Vec<Result<Option<Vec<u8>>, DataError>> {
+        stats::record(Stat::DbRead);
+        self.store.multi_get(keys).into_iter().map(|stored| match stored {
             Err(err) => Err(DataError::FromStore(err.into_string())),
-            Ok(db) => {
-                let rocks_db = RocksDb { store: db };
-                Ok(rocks_db)
+            Ok(None) => Ok(None),
+            Ok(Some(stored)) if self.is_expired(&stored) => Ok(None),
+            Ok(Some(stored)) => self.codec.decode_owned(stored).map(Some)
+        }).collect()
+    }
+
+    fn key_bounds(&self) -> Result<Option<(Vec<u8>, Vec<u8>)>, DataError> {
+        stats::record(Stat::DbScan);
+        let mut iter = self.store.raw_iterator();
+        iter.seek_to_first();
+        let Some(first) = iter.key().map(|key| key.to_vec())
+            else { return Self::iter_status(&iter).map(|_| None) };
+        iter.seek_to_last();
+        let Some(last) = iter.key().map(|key| key.to_vec())
+            else { return Self::iter_status(&iter).map(|_| None) };
+        Ok(Some((first, last)))
+    }
+
+    fn scan_keys(&self, start: Option<&[u8]>, end: Option<&[u8]>,
+                 visit: &mut dyn FnMut(&[u8]) -> bool) -> Result<(), DataError> {
+        // the raw iterator hands out borrowed keys and never copies values out
+        let (_, read_opts) = Self::scan_options(start, end);
+        stats::record(Stat::DbScan);
+        let mut iter = self.store.raw_iterator_opt(read_opts);
+        match start {
+            Some(start) => iter.seek(start),
+            None => iter.seek_to_first()
+        }
+
+        while iter.valid() {
+            let Some(key) = iter.key()
+                else { break };
+            if !visit(key) { break; }
+            iter.next();
+        }
+
+        match iter.status() {
+            Err(err) => Err(DataError::FromStore(err.into_string())),
+            Ok(_) => Ok(())
+        }
+    }
+
+    fn write_batch(&self, ops: &[BatchOp]) -> Result<(), DataError> {
+        self.check_writable()?;
+        let mut batch = WriteBatch::default();
+        for op in ops {
+            match op {
+                BatchOp::Put(key, value) => {
+                    Self::check_encodable(key)?;
+                    self.check_append(key)?;
+                    batch.put(key, self.codec.encode(value)?)
+                },
+                BatchOp::Delete(key) => {
+                    if self.append_only && !merge::is_reserved(key) { return Err(DataError::OperationNotAllowed); }
+                    batch.delete(key)
+                }
             }
         }
+
+        stats::record(Stat::DbWrite);
+        match self.store.write(batch) {
+            Err(err) => Err(self.write_failed(err.into())),
+            Ok(_) => Ok(())
+        }
     }
 
-    fn with_options() -> Options {
-        let mut opts = Options::default();
-        opts.create_if_missing(true);
-        opts.create_missing_column_families(true);
-        opts
+    fn ingest(&self, shards: &[&[(Vec<u8>, Vec<u8>)]]) -> Result<(), DataError> {
+        self.check_writable()?;
+        for (key, _) in shards.iter().flat_map(|shard| shard.iter()) {
+            Self::check_encodable(key)?;
+            self.check_append(key)?;
+        }
+        let load_id = NEXT_BULK_LOAD.fetch_add(1, Ordering::Relaxed);
+        let paths: Vec<PathBuf> = (0..shards.len())
+            .map(|shard| self.store.path().join(format!("bulk_load_{load_id}_{shard}.sst")))
+            .collect();
+
+        let written: Result<(), DataError> = thread::scope(|scope| {
+            let writers: Vec<_> = shards.iter().zip(&paths)
+                .map(|(shard, path)| scope.spawn(move || self.write_sst(shard, path)))
+                .collect();
+            writers.into_iter()
+                .map(|writer| writer.join().unwrap_or(Err(DataError::FromStore("sst writer panicked".to_string()))))
+                .collect()
+        });
+
+        // moved files are already gone by now, this only cleans up after a failed load
+        let ingested = written.and_then(|_| {
+            let mut ingest_opts = IngestExternalFileOptions::default();
+            ingest_opts.set_move_files(true);
+            stats::record(Stat::DbWrite);
+            match self.store.ingest_external_file_opts(&ingest_opts, paths.clone()) {
+                Err(err) => Err(err.into()),
+                Ok(_) => Ok(())
+            }
+        });
+        for path in &paths { let _ = std::fs::remove_file(path); }
+        ingested.map_err(|err| self.write_failed(err))
     }
-}
 
-impl Db for RocksDb {
-    fn get_token(&self, key: &Vec<u8>) -> Result<Token, DataError> {
-        match self.store.get(key) {
-            Err(e) => Err(DataError::FromStore(e.into_string())),
-            Ok(None) => Err(DataError::DataNotFound),
-            Ok(Some(data)) => {
-                match deserialize_rmp_to::<Token>(&data) {
-                    Err(err) => Err(DataError::DeserializationError(err)),
-                    Ok(token) => Ok(token)
-                }
+    fn block_cache_usage(&self) -> Result<BlockCacheUsage, DataError> {
+        let property = |name: &str| match self.store.property_int_value(name) {
+            Err(err) => Err(DataError::FromStore(err.into_string())),
+            Ok(value) => Ok(value.unwrap_or(0))
+        };
+
+        Ok(BlockCacheUsage {
+            usage: property("rocksdb.block-cache-usage")?,
+            pinned_usage: property("rocksdb.block-cache-pinned-usage")?,
+            capacity: property("rocksdb.block-cache-capacity")?
+        })
+    }
+
+    fn set_auto_compaction(&self, enabled: bool) -> Result<(), DataError> {
+        if self.secondary { return Err(DataError::ReadOnly); }
+        let options = [("disable_auto_compactions", if enabled { "false" } else { "true" })];
+        if let Err(err) = self.store.set_options(&options) { return Err(err.into()); }
+
+        let column_families = DBWithThreadMode::<MultiThreaded>::list_cf(&self.opts, self.store.path())
+            .unwrap_or_default();
+        for name in column_families {
+            let Some(cf) = self.column_family(&name)
+                else { continue };
+            if let Err(err) = self.store.set_options_cf(&cf, &options) { return Err(err.into()); }
+        }
+        Ok(())
+    }
+
+    fn compact(&self) -> Result<(), DataError> {
+        self.check_writable()?;
+        // forcing the bottommost level rewrites it as well, leaving as few files as possible
+        let mut compact_opts = CompactOptions::default();
+        compact_opts.set_bottommost_level_compaction(BottommostLevelCompaction::Force);
+        self.store.compact_range_opt(None::<&[u8]>, None::<&[u8]>, &compact_opts);
+
+        let column_families = DBWithThreadMode::<MultiThreaded>::list_cf(&self.opts, self.store.path())
+            .unwrap_or_default();
+        for name in column_families {
+            if let Some(cf) = self.column_family(&name) {
+                self.store.compact_range_cf_opt(&cf, None::<&[u8]>, None::<&[u8]>, &compact_opts);
             }
         }
+        Ok(())
     }
 
-    fn save_token(&self, key: &Vec<u8>, token_ref: &Arc<RwLock<Token>>) -> Result<(), DataError> {
-        let Ok(token) = token_ref.write()
-            else { return Err(DataError::Poisoned) };
+    fn checkpoint(&self, dest: &Path) -> Result<(), DataError> {
+        let checkpoint = match Checkpoint::new(&self.store) {
+            Err(err) => return Err(DataError::FromStore(err.into_string())),
+            Ok(checkpoint) => checkpoint
+        };
+        match checkpoint.create_checkpoint(dest) {
+            Err(err) => Err(err.into()),
+            Ok(_) => Ok(())
+        }
+    }
 
-        match serialize_to_bytes_rmp(token.deref()) {
-            Ok(data) => self.save_data(key, &data),
-            Err(err) => Err(DataError::SerializationError(err))
+    fn changes_since(&self, since: u64, visit: &mut dyn FnMut(ChangeRecord) -> bool) -> Result<(), DataError> {
+        let updates = match self.store.get_updates_since(since) {
+            Err(err) => return Err(err.into()),
+            Ok(updates) => updates
+        };
+
+        for update in updates {
+            let (sequence, batch) = match update {
+                Err(err) => return Err(err.into()),
+                Ok(update) => update
+            };
+            let mut batch_records = BatchRecords::new(sequence);
+            batch.iterate_cf(&mut batch_records);
+
+            for record in batch_records.records {
+                // the first batch can start before `since`
+                if record.sequence() < since { continue; }
+                let record = match record {
+                    ChangeRecord::Put { sequence, key, value } if !merge::is_raw(&key) =>
+                        ChangeRecord::Put { sequence, value: self.codec.decode_owned(value)?, key },
+                    ChangeRecord::Merge { sequence, key, operand } if !merge::is_raw(&key) =>
+                        ChangeRecord::Merge { sequence, operand: self.codec.decode_owned(operand)?, key },
+                    raw_or_delete => raw_or_delete
+                };
+                if !visit(record) { return Ok(()); }
+            }
         }
+        Ok(())
     }
 
-    fn get_data(&self, key: &Vec<u8>) -> Result<Vec<u8>, DataError> {
-        match self.store.get(key) {
+    fn disk_size(&self) -> Result<u64, DataError> {
+        match self.store.property_int_value("rocksdb.total-sst-files-size") {
+            Err(err) => Err(DataError::FromStore(err.into_string())),
+            Ok(size) => Ok(size.unwrap_or(0))
+        }
+    }
+
+    fn catch_up(&self) -> Result<bool, DataError> {
+        if !self.secondary { return Ok(false); }
+        match self.store.try_catch_up_with_primary() {
+            Err(err) => Err(DataError::FromStore(err.into_string())),
+            Ok(_) => Ok(true)
+        }
+    }
+
+    fn replica_lag(&self) -> Result<u64, DataError> {
+        let Some(probe_path) = &self.lag_probe_path
+            else { return Ok(0) };
+
+        let mut probe = self.lag_probe.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let primary_sequence = match probe.as_ref() {
+            Some(probe) => {
+                probe.try_catch_up_with_primary()?;
+                probe.latest_sequence_number()
+            },
+            None => {
+                let column_families = DBWithThreadMode::<MultiThreaded>::list_cf(&self.opts, self.store.path())
+                    .unwrap_or_default();
+                let opened = DBWithThreadMode::open_cf_as_secondary(&self.opts, self.store.path(),
+                                                                    probe_path.as_path(), column_families)?;
+                probe.insert(opened).latest_sequence_number()
+            }
+        };
+        Ok(primary_sequence.saturating_sub(self.store.latest_sequence_number()))
+    }
+
+    fn write_amplification(&self) -> Result<f64, DataError> {
+        if self.opts.get_statistics().is_none() {
+            return Err(DataError::FromStore("statistics aren't enabled".to_string()));
+        }
+
+        let user_bytes = self.opts.get_ticker_count(Ticker::BytesWritten);
+        if user_bytes == 0 { return Ok(0.0); }
+
+        let sst_bytes = self.opts.get_ticker_count(Ticker::FlushWriteBytes)
+            + self.opts.get_ticker_count(Ticker::CompactWriteBytes);
+        Ok(sst_bytes as f64 / user_bytes as f64)
+    }
+
+    fn verify(&self) -> Result<u64, DataError> {
+        let mut read_opts = ReadOptions::default();
+        read_opts.set_verify_checksums(true);
+        // a full pass would otherwise evict the whole working set from the block cache
+        read_opts.fill_cache(false);
+
+        // decoding as well catches values whose header is damaged
+        let mut entries = 0;
+        self.visit_decoded(self.store.iterator_opt(IteratorMode::Start, read_opts), &mut |_, _| {
+            entries += 1;
+            true
+        })?;
+        Ok(entries)
+    }
+
+    fn value_size(&self, key: &Vec<u8>) -> Result<Option<usize>, DataError> {
+        // a pinned read returns the stored bytes without copying or decoding them
+        stats::record(Stat::DbRead);
+        match self.store.get_pinned(key) {
+            Err(e) => Err(DataError::FromStore(e.into_string())),
+            Ok(data) => Ok(data.map(|data| data.len()))
+        }
+    }
+
+    fn writable(&self) -> Result<(), DataError> {
+        self.check_writable()
+    }
+
+    fn append_only(&self) -> bool {
+        self.append_only
+    }
+
+    fn get_in(&self, namespace: &str, key: &Vec<u8>) -> Result<Vec<u8>, DataError> {
+        let Some(cf) = self.column_family(namespace)
+            else { return Err(DataError::DataNotFound) };
+
+        stats::record(Stat::DbRead);
+        match self.store.get_cf(&cf, key) {
             Err(e) => Err(DataError::FromStore(e.into_string())),
             Ok(None) => Err(DataError::DataNotFound),
-            Ok(Some(data)) => Ok(data)
+            Ok(Some(data)) => self.codec.decode_owned(data)
         }
     }
 
-    fn save_data(&self, key: &Vec<u8>, data: &Vec<u8>) -> Result<(), DataError> {
-        match self.store.put(key, data) {
-            Err(err) => Err(DataError::FromStore(err.into_string())),
+    fn save_in(&self, namespace: &str, key: &Vec<u8>, data: &[u8]) -> Result<(), DataError> {
+        self.check_writable()?;
+        let cf = self.create_column_family(namespace)?;
+        if self.append_only {
+            stats::record(Stat::DbRead);
+            match self.store.get_pinned_cf(&cf, key) {
+                Err(err) => return Err(err.into()),
+                Ok(Some(_)) => return Err(DataError::AlreadyExists),
+                Ok(None) => {}
+            }
+        }
+        let stored = self.codec.encode(data)?;
+        stats::record(Stat::DbWrite);
+        match self.store.put_cf(&cf, key, stored) {
+            Err(err) => Err(self.write_failed(err.into())),
             Ok(_) => Ok(())
         }
     }
+
+    fn scan_in(&self, namespace: &str, start: Option<&[u8]>, end: Option<&[u8]>,
+               visit: &mut dyn FnMut(&[u8], &[u8]) -> bool) -> Result<(), DataError> {
+        let Some(cf) = self.column_family(namespace)
+            else { return Ok(()) };
+
+        let (mode, read_opts) = Self::scan_options(start, end);
+        stats::record(Stat::DbScan);
+        self.visit_decoded(self.store.iterator_cf_opt(&cf, read_opts, mode), visit)
+    }
 }
 
-type TokenCache = Cache<Vec<u8>, Arc<RwLock<Token>>>;
-type DataCache = Cache<Vec<u8>, Arc<RwLock<Vec<u8>>>>;
+type CacheKey = (String, Vec<u8>);
+type TokenCache = Cache<CacheKey, Arc<RwLock<Token>>>;
+type DataCache = Cache<CacheKey, Arc<RwLock<Vec<u8>>>>;
+type SharedDataCache = Cache<CacheKey, Arc<Vec<u8>>>;
+type LazyTokenCache = Cache<CacheKey, Arc<LazyToken>>;
+type HandleCache = Cache<String, Arc<dyn Db>>;
 
 #[cfg(test)]
 mod tests {
@@ -223,4 +2946,205 @@ mod tests {
     //     let result = add(2, 2);
     //     assert_eq!(result, 4);
     // }
+
+    fn temp_partition(name: &str) -> String {
+        let path = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&path);
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn concurrent_saves_leave_cache_matching_store() {
+        let partition_id = temp_partition("pneumatic_data_concurrent_saves");
+        let key = b"contended".to_vec();
+
+        let writers: Vec<_> = (0..8u8).map(|writer| {
+            let partition_id = partition_id.clone();
+            let key = key.clone();
+            std::thread::spawn(move || {
+                for round in 0..200u8 {
+                    SafeDataProvider::save_data(&key, vec![writer, round], &partition_id).unwrap();
+                }
+            })
+        }).collect();
+        for writer in writers { writer.join().unwrap(); }
+
+        let cached = SafeDataProvider::get_data(&key, &partition_id).unwrap();
+        let stored = SafeDataProvider::get_db(&partition_id).unwrap().get_data(&key).unwrap();
+        assert_eq!(*cached.read().unwrap(), stored);
+    }
+
+    #[test]
+    fn swap_keys_keeps_expiry() {
+        let partition_id = temp_partition("pneumatic_data_swap_keys");
+        let (expiring, plain) = (b"expiring".to_vec(), b"plain".to_vec());
+        let expires_at = SystemTime::now() + Duration::from_secs(3600);
+
+        SafeDataProvider::save_data_until(&expiring, b"lease", expires_at, &partition_id).unwrap();
+        SafeDataProvider::save_data(&plain, b"forever".to_vec(), &partition_id).unwrap();
+        SafeDataProvider::swap_keys(&expiring, &plain, &partition_id).unwrap();
+
+        let db = SafeDataProvider::get_db(&partition_id).unwrap();
+        let (value, expiry) = db.get_data_with_expiry(&plain).unwrap();
+        assert_eq!(value, b"lease");
+        assert_eq!(expiry.map(to_millis), Some(to_millis(expires_at)));
+        assert_eq!(db.get_data_with_expiry(&expiring).unwrap(), (b"forever".to_vec(), None));
+        assert_eq!(*SafeDataProvider::get_data(&plain, &partition_id).unwrap().read().unwrap(), b"lease");
+    }
+
+    #[test]
+    fn nested_errors_are_reported_once() {
+        static REPORTED: Mutex<Vec<(String, &'static str)>> = Mutex::new(vec![]);
+        let outer = temp_partition("pneumatic_data_nested_errors_outer");
+        let inner = temp_partition("pneumatic_data_nested_errors_inner");
+        // other tests may fail meanwhile, so only these partitions' reports count
+        let (outer_id, inner_id) = (outer.clone(), inner.clone());
+        SafeDataProvider::set_error_hook(move |_, partition_id, op| {
+            if partition_id == outer_id || partition_id == inner_id {
+                REPORTED.lock().unwrap().push((partition_id.to_string(), op));
+            }
+        });
+        let loader_partition = inner.clone();
+        SafeDataProvider::set_loader(&outer, move |key, _| {
+            SafeDataProvider::get_data(&key.to_vec(), &loader_partition).map(|data| data.read().unwrap().clone())
+        });
+
+        let missing = b"missing".to_vec();
+        assert_eq!(SafeDataProvider::get_data(&missing, &outer).err(), Some(DataError::DataNotFound));
+        assert_eq!(*REPORTED.lock().unwrap(), vec![(outer.clone(), "get_data")]);
+
+        // the loader's failure didn't leave the thread thinking it's still inside a call
+        assert_eq!(SafeDataProvider::get_data(&missing, &inner).err(), Some(DataError::DataNotFound));
+        assert_eq!(REPORTED.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn memory_routed_writes_follow_the_partition_rules() {
+        let partition_id = temp_partition("pneumatic_data_routed_append_only");
+        let mut config = DataConfig::default();
+        config.partitions.insert(partition_id.clone(), PartitionConfig { append_only: true, ..PartitionConfig::default() });
+        let durable: Arc<dyn Db> = Arc::new(RocksDb::create(&partition_id, &config).unwrap());
+        let memory = Arc::new(memory::MemoryDb::default());
+        let db = RoutedDb::new(durable.clone(), memory.clone(), Arc::new(|_: &[u8]| BackendId::Memory));
+
+        let key = b"key".to_vec();
+        db.save_data(&key, b"first").unwrap();
+        assert_eq!(db.save_data(&key, b"second"), Err(DataError::AlreadyExists));
+        assert_eq!(db.write_batch(&[BatchOp::Put(key.clone(), b"second".to_vec())]), Err(DataError::AlreadyExists));
+        assert_eq!(db.write_batch(&[BatchOp::Delete(key.clone())]), Err(DataError::OperationNotAllowed));
+        assert_eq!(db.get_data(&key).unwrap(), b"first");
+
+        // the router doesn't get a say over the provider's own keys
+        let counter = merge::counter_key("orders");
+        assert_eq!(db.increment(&counter).unwrap(), 1);
+        assert_eq!(durable.increment(&counter).unwrap(), 2);
+        assert_eq!(memory.get_stored(&counter), Err(DataError::DataNotFound));
+    }
+
+    #[test]
+    fn appending_to_an_expired_value_starts_over() {
+        let partition_id = temp_partition("pneumatic_data_append_expired");
+        let db = RocksDb::create(&partition_id, &DataConfig::default()).unwrap();
+        let key = b"key".to_vec();
+        db.save_data_until(&key, b"stale", SystemTime::UNIX_EPOCH + Duration::from_secs(1)).unwrap();
+        db.merge(&key, b"new").unwrap();
+        assert_eq!(db.get_data_with_expiry(&key).unwrap(), (b"new".to_vec(), None));
+
+        // a live value keeps its expiry and gets appended to
+        let expires_at = SystemTime::now() + Duration::from_secs(3600);
+        db.save_data_until(&key, b"live", expires_at).unwrap();
+        db.merge(&key, b"+more").unwrap();
+        let (data, stored_expiry) = db.get_data_with_expiry(&key).unwrap();
+        assert_eq!(data, b"live+more");
+        assert_eq!(stored_expiry.map(to_millis), Some(to_millis(expires_at)));
+    }
+
+    #[test]
+    fn flush_covers_column_families() {
+        let partition_id = temp_partition("pneumatic_data_flush_column_families");
+        let db = RocksDb::create(&partition_id, &DataConfig::default()).unwrap();
+        db.save_in("orders", &b"key".to_vec(), b"value").unwrap();
+        db.flush().unwrap();
+
+        let cf = db.column_family("orders").unwrap();
+        let unflushed = db.store.property_int_value_cf(&cf, "rocksdb.num-entries-active-mem-table").unwrap();
+        assert_eq!(unflushed, Some(0));
+    }
+
+    // the options RocksDB last persisted for the partition, which SetOptions rewrites
+    fn latest_options(partition_id: &str) -> String {
+        let (_, latest) = std::fs::read_dir(partition_id).unwrap()
+            .filter_map(|entry| entry.unwrap().file_name().into_string().ok())
+            .filter_map(|name| {
+                let number = name.strip_prefix("OPTIONS-")?.parse::<u64>().ok()?;
+                Some((number, name))
+            })
+            .max()
+            .unwrap();
+        std::fs::read_to_string(Path::new(partition_id).join(latest)).unwrap()
+    }
+
+    #[test]
+    fn routed_partitions_pause_durable_compaction() {
+        let partition_id = temp_partition("pneumatic_data_routed_compaction");
+        let durable: Arc<dyn Db> = Arc::new(RocksDb::create(&partition_id, &DataConfig::default()).unwrap());
+        let db = RoutedDb::new(durable, Arc::new(memory::MemoryDb::default()), Arc::new(|_: &[u8]| BackendId::Memory));
+
+        db.set_auto_compaction(false).unwrap();
+        assert!(latest_options(&partition_id).contains("disable_auto_compactions=true"));
+        db.set_auto_compaction(true).unwrap();
+        assert!(latest_options(&partition_id).contains("disable_auto_compactions=false"));
+    }
+
+    #[test]
+    fn portable_import_keeps_counters_and_refcounts() {
+        let src = temp_partition("pneumatic_data_portable_src");
+        let dest = temp_partition("pneumatic_data_portable_dest");
+        let id = SafeDataProvider::next_id("orders", &src).unwrap();
+        let hash = SafeDataProvider::save_content_addressed(b"blob", &src).unwrap();
+        SafeDataProvider::save_content_addressed(b"blob", &src).unwrap();
+        SafeDataProvider::save_data(&b"user".to_vec(), b"value".to_vec(), &src).unwrap();
+        let mut stream = vec![];
+        SafeDataProvider::export_portable(&src, &mut stream).unwrap();
+
+        // a destination whose codec puts a header on every value
+        let mut config = DataConfig::default();
+        config.partitions.insert(dest.clone(), PartitionConfig {
+            checksum_on_write: true,
+            encryption_key: Some(EncryptionKey::Bytes(vec![7; 32])),
+            ..PartitionConfig::default()
+        });
+        let db: Arc<dyn Db> = Arc::new(RocksDb::create(&dest, &config).unwrap());
+        portable::import(db.as_ref(), &stream[..]).unwrap();
+        SafeDataProvider::get_db_handles().insert(dest.clone(), db);
+
+        assert_eq!(SafeDataProvider::next_id("orders", &dest).unwrap(), id + 1);
+        assert_eq!(SafeDataProvider::get_content(&hash, &dest).unwrap(), b"blob");
+        assert_eq!(SafeDataProvider::release_content(&hash, &dest).unwrap(), 1);
+        assert_eq!(SafeDataProvider::release_content(&hash, &dest).unwrap(), 0);
+        assert_eq!(*SafeDataProvider::get_data(&b"user".to_vec(), &dest).unwrap().read().unwrap(), b"value");
+    }
+
+    #[test]
+    fn failed_portable_import_leaves_no_partition() {
+        let src = temp_partition("pneumatic_data_portable_failing_src");
+        let dest = temp_partition("pneumatic_data_portable_failing_dest");
+        SafeDataProvider::save_data(&b"key".to_vec(), b"value".to_vec(), &src).unwrap();
+        let mut stream = vec![];
+        SafeDataProvider::export_portable(&src, &mut stream).unwrap();
+
+        let last = stream.len() - 1;
+        stream[last] ^= 0xFF;
+        assert_eq!(SafeDataProvider::import_portable(&dest, &stream[..]), Err(DataError::ChecksumMismatch));
+        assert!(!SafeDataProvider::is_open(&dest));
+
+        // creating the partition again only works if the failed import destroyed it
+        stream[last] ^= 0xFF;
+        SafeDataProvider::import_portable(&dest, &stream[..]).unwrap();
+        assert_eq!(*SafeDataProvider::get_data(&b"key".to_vec(), &dest).unwrap().read().unwrap(), b"value");
+    }
+
+    fn to_millis(time: SystemTime) -> u128 {
+        time.duration_since(std::time::UNIX_EPOCH).unwrap().as_millis()
+    }
 }
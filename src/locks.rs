@@ -0,0 +1,40 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use crate::contention;
+
+// Keys hash onto a fixed set of stripes rather than each getting its own lock, so the table
+// never grows; unrelated keys sharing a stripe only costs a little contention.
+const STRIPES: usize = 256;
+
+static KEY_LOCKS: OnceLock<Vec<Mutex<()>>> = OnceLock::new();
+
+/// Held across a store access and the cache update that follows it, so the cache always ends
+/// up holding whatever the last store write for the key was.
+pub(crate) fn lock_key(key: &[u8], partition_id: &str) -> MutexGuard<'static, ()> {
+    lock_stripe(stripe(key, partition_id), key, partition_id)
+}
+
+/// Locks every stripe the keys map to, in stripe order so concurrent batches can't deadlock.
+pub(crate) fn lock_keys<'a>(keys: impl Iterator<Item=&'a Vec<u8>>, partition_id: &str)
+                            -> Vec<MutexGuard<'static, ()>> {
+    let mut stripes: Vec<(usize, &Vec<u8>)> = keys.map(|key| (stripe(key, partition_id), key)).collect();
+    stripes.sort_unstable();
+    stripes.dedup_by_key(|(stripe, _)| *stripe);
+    stripes.into_iter().map(|(stripe, key)| lock_stripe(stripe, key, partition_id)).collect()
+}
+
+// Contention is counted against the key being locked, which on a shared stripe might not be
+// the key whose holder it waited for.
+fn lock_stripe(stripe: usize, key: &[u8], partition_id: &str) -> MutexGuard<'static, ()> {
+    // the lock guards no data of its own, so one poisoned by a panicking writer is still usable
+    let locks = KEY_LOCKS.get_or_init(|| (0..STRIPES).map(|_| Mutex::new(())).collect());
+    contention::lock(&locks[stripe], key, partition_id)
+}
+
+fn stripe(key: &[u8], partition_id: &str) -> usize {
+    let mut hasher = DefaultHasher::new();
+    partition_id.hash(&mut hasher);
+    key.hash(&mut hasher);
+    (hasher.finish() % STRIPES as u64) as usize
+}
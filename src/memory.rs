@@ -0,0 +1,211 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ops::{Bound, Deref};
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use pneumatic_core::encoding::{deserialize_rmp_to, serialize_to_bytes_rmp};
+use pneumatic_core::tokens::Token;
+use crate::{merge, BatchOp, BlockCacheUsage, DataError, Db};
+
+type Entries = BTreeMap<Vec<u8>, Vec<u8>>;
+
+static MEMORY_BACKENDS: OnceLock<Mutex<HashMap<String, Arc<MemoryDb>>>> = OnceLock::new();
+
+/// A backend holding everything in memory, for hot data that doesn't have to survive a
+/// restart. Values are kept as given, without the partition's compression or encryption.
+#[derive(Default)]
+pub(crate) struct MemoryDb {
+    entries: RwLock<Entries>,
+    namespaces: RwLock<HashMap<String, Entries>>
+}
+
+/// The partition's memory backend. It lives for as long as the process (or until the
+/// partition is destroyed) rather than as long as a handle, so closing an idle partition
+/// doesn't drop its data.
+pub(crate) fn for_partition(partition_id: &str) -> Arc<MemoryDb> {
+    let mut backends = memory_backends().lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    backends.entry(partition_id.to_string()).or_default().clone()
+}
+
+pub(crate) fn discard(partition_id: &str) {
+    let mut backends = memory_backends().lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    backends.remove(partition_id);
+}
+
+fn memory_backends() -> &'static Mutex<HashMap<String, Arc<MemoryDb>>> {
+    MEMORY_BACKENDS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+impl MemoryDb {
+    // Scans visit a copy of the range, which both gives them a snapshot and keeps a slow
+    // visitor from holding up writers.
+    fn visit_range(entries: &Entries, start: Option<&[u8]>, end: Option<&[u8]>,
+                   visit: &mut dyn FnMut(&[u8], &[u8]) -> bool) {
+        let lower = start.map_or(Bound::Unbounded, Bound::Included);
+        let upper = end.map_or(Bound::Unbounded, Bound::Excluded);
+        let range: Vec<(Vec<u8>, Vec<u8>)> = entries.range::<[u8], _>((lower, upper))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        for (key, value) in range {
+            if !visit(&key, &value) { break; }
+        }
+    }
+}
+
+impl Db for MemoryDb {
+    fn get_token(&self, key: &Vec<u8>) -> Result<Token, DataError> {
+        let data = self.get_data(key)?;
+        match deserialize_rmp_to::<Token>(&data) {
+            Err(err) => Err(DataError::DeserializationError(err.to_string())),
+            Ok(token) => Ok(token)
+        }
+    }
+
+    fn save_token(&self, key: &Vec<u8>, token_ref: &Arc<RwLock<Token>>) -> Result<(), DataError> {
+        let Ok(token) = token_ref.write()
+            else { return Err(DataError::Poisoned) };
+
+        match serialize_to_bytes_rmp(token.deref()) {
+            Ok(data) => self.save_data(key, &data),
+            Err(err) => Err(DataError::SerializationError(err.to_string()))
+        }
+    }
+
+    fn get_data(&self, key: &Vec<u8>) -> Result<Vec<u8>, DataError> {
+        self.entries.read()?.get(key).cloned().ok_or(DataError::DataNotFound)
+    }
+
+    fn save_data(&self, key: &Vec<u8>, data: &[u8]) -> Result<(), DataError> {
+        self.entries.write()?.insert(key.clone(), data.to_vec());
+        Ok(())
+    }
+
+    fn get_stored(&self, key: &Vec<u8>) -> Result<Vec<u8>, DataError> {
+        self.get_data(key)
+    }
+
+    fn put_stored(&self, entries: &[(Vec<u8>, Vec<u8>)]) -> Result<(), DataError> {
+        let mut stored = self.entries.write()?;
+        for (key, value) in entries { stored.insert(key.clone(), value.clone()); }
+        Ok(())
+    }
+
+    fn merge(&self, key: &Vec<u8>, operand: &[u8]) -> Result<(), DataError> {
+        self.entries.write()?.entry(key.clone()).or_default().extend_from_slice(operand);
+        Ok(())
+    }
+
+    fn increment(&self, key: &Vec<u8>) -> Result<u64, DataError> {
+        let mut entries = self.entries.write()?;
+        let count = match entries.get(key) {
+            None => 0,
+            Some(stored) => merge::counter_value(stored)
+                .ok_or_else(|| DataError::DeserializationError("counter value is corrupt".to_string()))?
+        } + 1;
+        entries.insert(key.clone(), count.to_le_bytes().to_vec());
+        Ok(count)
+    }
+
+    fn adjust_refcount(&self, key: &Vec<u8>, delta: i64) -> Result<i64, DataError> {
+        let mut entries = self.entries.write()?;
+        let count = match entries.get(key) {
+            None => 0,
+            Some(stored) => merge::refcount_value(stored)
+                .ok_or_else(|| DataError::DeserializationError("refcount value is corrupt".to_string()))?
+        } + delta;
+        entries.insert(key.clone(), count.to_le_bytes().to_vec());
+        Ok(count)
+    }
+
+    fn flush(&self) -> Result<(), DataError> {
+        Ok(())
+    }
+
+    fn sync_wal(&self) -> Result<(), DataError> {
+        Ok(())
+    }
+
+    fn scan(&self, start: Option<&[u8]>, end: Option<&[u8]>,
+            visit: &mut dyn FnMut(&[u8], &[u8]) -> bool) -> Result<(), DataError> {
+        let entries = self.entries.read()?;
+        Self::visit_range(&entries, start, end, visit);
+        Ok(())
+    }
+
+    fn scan_stored(&self, start: Option<&[u8]>, end: Option<&[u8]>,
+                   visit: &mut dyn FnMut(&[u8], &[u8]) -> bool) -> Result<(), DataError> {
+        self.scan(start, end, visit)
+    }
+
+    fn scan_keys(&self, start: Option<&[u8]>, end: Option<&[u8]>,
+                 visit: &mut dyn FnMut(&[u8]) -> bool) -> Result<(), DataError> {
+        self.scan(start, end, &mut |key, _| visit(key))
+    }
+
+    fn write_batch(&self, ops: &[BatchOp]) -> Result<(), DataError> {
+        let mut entries = self.entries.write()?;
+        for op in ops {
+            match op {
+                BatchOp::Put(key, value) => { entries.insert(key.clone(), value.clone()); },
+                BatchOp::Delete(key) => { entries.remove(key); }
+            }
+        }
+        Ok(())
+    }
+
+    fn ingest(&self, shards: &[&[(Vec<u8>, Vec<u8>)]]) -> Result<(), DataError> {
+        let mut entries = self.entries.write()?;
+        for (key, value) in shards.iter().flat_map(|shard| shard.iter()) {
+            entries.insert(key.clone(), value.clone());
+        }
+        Ok(())
+    }
+
+    fn write_amplification(&self) -> Result<f64, DataError> {
+        Ok(0.0)
+    }
+
+    fn block_cache_usage(&self) -> Result<BlockCacheUsage, DataError> {
+        Ok(BlockCacheUsage::default())
+    }
+
+    fn compact(&self) -> Result<(), DataError> {
+        Ok(())
+    }
+
+    fn checkpoint(&self, _dest: &Path) -> Result<(), DataError> {
+        Err(DataError::FromStore("in-memory partitions can't be checkpointed".to_string()))
+    }
+
+    fn disk_size(&self) -> Result<u64, DataError> {
+        Ok(0)
+    }
+
+    fn catch_up(&self) -> Result<bool, DataError> {
+        Ok(false)
+    }
+
+    fn verify(&self) -> Result<u64, DataError> {
+        Ok(self.entries.read()?.len() as u64)
+    }
+
+    fn get_in(&self, namespace: &str, key: &Vec<u8>) -> Result<Vec<u8>, DataError> {
+        let namespaces = self.namespaces.read()?;
+        namespaces.get(namespace)
+            .and_then(|entries| entries.get(key))
+            .cloned()
+            .ok_or(DataError::DataNotFound)
+    }
+
+    fn save_in(&self, namespace: &str, key: &Vec<u8>, data: &[u8]) -> Result<(), DataError> {
+        let mut namespaces = self.namespaces.write()?;
+        namespaces.entry(namespace.to_string()).or_default().insert(key.clone(), data.to_vec());
+        Ok(())
+    }
+
+    fn scan_in(&self, namespace: &str, start: Option<&[u8]>, end: Option<&[u8]>,
+               visit: &mut dyn FnMut(&[u8], &[u8]) -> bool) -> Result<(), DataError> {
+        let namespaces = self.namespaces.read()?;
+        if let Some(entries) = namespaces.get(namespace) { Self::visit_range(entries, start, end, visit); }
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+use std::time::SystemTime;
+use rocksdb::MergeOperands;
+use crate::composite_key;
+use crate::envelope::ValueCodec;
+
+/// Keys under this prefix hold the provider's own bookkeeping rather than user values.
+const RESERVED_PREFIX: &[u8] = b"\0pneumatic_data/";
+const COUNTER_PREFIX: &[u8] = b"\0pneumatic_data/id/";
+const RING_PREFIX: &[u8] = b"\0pneumatic_data/ring/";
+const BLOB_PREFIX: &[u8] = b"\0pneumatic_data/blob/";
+const REFCOUNT_PREFIX: &[u8] = b"\0pneumatic_data/refs/";
+
+pub(crate) fn is_reserved(key: &[u8]) -> bool {
+    key.starts_with(RESERVED_PREFIX)
+}
+
+/// Whether the key's values are plain numbers the merge operator reads as stored (counters and
+/// refcounts), so they never go through the codec.
+pub(crate) fn is_raw(key: &[u8]) -> bool {
+    key.starts_with(COUNTER_PREFIX) || key.starts_with(REFCOUNT_PREFIX)
+}
+
+pub(crate) fn counter_key(namespace: &str) -> Vec<u8> {
+    [COUNTER_PREFIX, namespace.as_bytes()].concat()
+}
+
+/// A counter's value as stored, or None if the bytes aren't one.
+pub(crate) fn counter_value(stored: &[u8]) -> Option<u64> {
+    Some(u64::from_le_bytes(stored.try_into().ok()?))
+}
+
+pub(crate) fn blob_key(hash: &[u8]) -> Vec<u8> {
+    [BLOB_PREFIX, hash].concat()
+}
+
+pub(crate) fn refcount_key(hash: &[u8]) -> Vec<u8> {
+    [REFCOUNT_PREFIX, hash].concat()
+}
+
+/// A refcount's value as stored, or None if the bytes aren't one.
+pub(crate) fn refcount_value(stored: &[u8]) -> Option<i64> {
+    Some(i64::from_le_bytes(stored.try_into().ok()?))
+}
+
+// A ring's head and slots share its base key as their namespace, the head with an empty id
+// and each slot with its index, so no two rings (or a ring's head and slots) collide.
+pub(crate) fn ring_head_key(base_key: &[u8]) -> Vec<u8> {
+    [RING_PREFIX, &composite_key(base_key, &[])].concat()
+}
+
+pub(crate) fn ring_slot_key(base_key: &[u8], slot: u64) -> Vec<u8> {
+    [RING_PREFIX, &composite_key(base_key, &(slot as u32).to_be_bytes())].concat()
+}
+
+/// A ring head: how many values have been written to the ring, and its size.
+pub(crate) fn ring_head_value(written: u64, ring_size: u32) -> Vec<u8> {
+    [written.to_le_bytes().as_slice(), &ring_size.to_le_bytes()].concat()
+}
+
+pub(crate) fn ring_head(stored: &[u8]) -> Option<(u64, u32)> {
+    let written = u64::from_le_bytes(stored.get(..8)?.try_into().ok()?);
+    let ring_size = u32::from_le_bytes(stored.get(8..)?.try_into().ok()?);
+    Some((written, ring_size))
+}
+
+/// The partition's merge operator. Counter keys add up their operands (plain little-endian
+/// u64s, not run through the codec), and refcount keys theirs (i64 deltas, likewise); every
+/// other key appends every operand to the existing
+/// value. Operands are encoded like any other value, so each one is decoded, and the result
+/// is encoded again. Adding and appending are both associative, so the same function also
+/// combines operands on their own. The existing value's expiry, if it has one, carries over
+/// to the result, unless it has already passed: an expired value counts as absent, so the
+/// result is the operands alone, without an expiry. That's judged when RocksDB merges (on a
+/// read or compaction), not when the operands were written. Returning None (a value or operand that doesn't decode) makes RocksDB fail
+/// the read.
+pub(crate) fn merge(codec: &ValueCodec, key: &[u8], existing: Option<&[u8]>, operands: &MergeOperands) -> Option<Vec<u8>> {
+    if key.starts_with(COUNTER_PREFIX) { return merge_counter(existing, operands); }
+    if key.starts_with(REFCOUNT_PREFIX) { return merge_refcount(existing, operands); }
+
+    let live = match existing {
+        None => None,
+        Some(existing) => match codec.expiry(existing).ok()? {
+            Some(expires_at) if expires_at <= SystemTime::now() => None,
+            expires_at => Some((existing, expires_at))
+        }
+    };
+    let (mut merged, expires_at) = match live {
+        None => (vec![], None),
+        Some((existing, expires_at)) => (codec.decode(existing).ok()?.into_owned(), expires_at)
+    };
+    for operand in operands.iter() {
+        merged.extend_from_slice(&codec.decode(operand).ok()?);
+    }
+    codec.encode_until(&merged, expires_at).ok()
+}
+
+fn merge_refcount(existing: Option<&[u8]>, operands: &MergeOperands) -> Option<Vec<u8>> {
+    let mut count = match existing {
+        None => 0,
+        Some(existing) => refcount_value(existing)?
+    };
+    for operand in operands.iter() {
+        count = count.checked_add(refcount_value(operand)?)?;
+    }
+    Some(count.to_le_bytes().to_vec())
+}
+
+fn merge_counter(existing: Option<&[u8]>, operands: &MergeOperands) -> Option<Vec<u8>> {
+    let mut count = match existing {
+        None => 0,
+        Some(existing) => counter_value(existing)?
+    };
+    for operand in operands.iter() {
+        count = count.checked_add(counter_value(operand)?)?;
+    }
+    Some(count.to_le_bytes().to_vec())
+}
@@ -0,0 +1,238 @@
+use std::io::{self, Read, Write};
+use crc32fast::Hasher;
+use crate::{merge, BatchOp, DataError, Db};
+
+// A portable export doesn't depend on how the partition stores anything: values are written
+// decoded, so whatever backend loads it can encode them its own way. Integers are LE:
+//   header: MAGIC, format version (u32), key count (u64)
+//   entries, in key order: key length (u32), key, value length (u32), value
+//   trailer: crc32 of everything before it (u32)
+// Counters and refcounts are the exception: their values are plain numbers that never go
+// through a codec, so they're exported as stored and imported without encoding. The rest of
+// the provider's reserved keys (rings, blobs) hold ordinary encoded values.
+const MAGIC: &[u8; 8] = b"PNEUPORT";
+const FORMAT_VERSION: u32 = 1;
+
+const IMPORT_BATCH: usize = 1024;
+
+/// What an export_portable stream holds, as written in its header and trailer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PortableManifest {
+    pub format_version: u32,
+    pub keys: u64,
+    /// crc32 of the stream up to its trailer.
+    pub checksum: u32
+}
+
+pub(crate) fn export(db: &dyn Db, mut writer: impl Write) -> Result<PortableManifest, DataError> {
+    // the count goes in the header, so it takes a pass of its own; expired values are left
+    // out of both passes
+    let mut keys = 0u64;
+    db.scan(None, None, &mut |_, _| {
+        keys += 1;
+        true
+    })?;
+
+    let mut hasher = Hasher::new();
+    let mut header = MAGIC.to_vec();
+    header.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    header.extend_from_slice(&keys.to_le_bytes());
+    write_hashed(&mut writer, &mut hasher, &header)?;
+
+    let mut written = 0u64;
+    let mut write_result = Ok(());
+    db.scan(None, None, &mut |key, value| {
+        written += 1;
+        if written > keys { return false; }
+        match write_field(&mut writer, &mut hasher, key).and_then(|_| write_field(&mut writer, &mut hasher, value)) {
+            Err(err) => {
+                write_result = Err(err);
+                false
+            },
+            Ok(_) => true
+        }
+    })?;
+    write_result?;
+    if written != keys {
+        return Err(DataError::FromStore("partition changed while it was being exported".to_string()));
+    }
+
+    let checksum = hasher.finalize();
+    writer.write_all(&checksum.to_le_bytes())?;
+    writer.flush()?;
+    Ok(PortableManifest { format_version: FORMAT_VERSION, keys, checksum })
+}
+
+pub(crate) fn import(db: &dyn Db, mut reader: impl Read) -> Result<PortableManifest, DataError> {
+    let mut hasher = Hasher::new();
+    let mut magic = [0u8; 8];
+    read_hashed(&mut reader, &mut hasher, &mut magic)?;
+    if &magic != MAGIC { return Err(invalid("not a portable export")); }
+
+    let mut format_version = [0u8; 4];
+    read_hashed(&mut reader, &mut hasher, &mut format_version)?;
+    let format_version = u32::from_le_bytes(format_version);
+    if format_version != FORMAT_VERSION {
+        return Err(invalid(&format!("unsupported portable export version {format_version}")));
+    }
+
+    let mut keys = [0u8; 8];
+    read_hashed(&mut reader, &mut hasher, &mut keys)?;
+    let keys = u64::from_le_bytes(keys);
+
+    let (mut batch, mut raw) = (Vec::with_capacity(IMPORT_BATCH), vec![]);
+    for _ in 0..keys {
+        let key = read_field(&mut reader, &mut hasher)?;
+        let value = read_field(&mut reader, &mut hasher)?;
+        match merge::is_raw(&key) {
+            true => raw.push((key, value)),
+            false => batch.push(BatchOp::Put(key, value))
+        }
+        if batch.len() + raw.len() == IMPORT_BATCH { write(db, &mut batch, &mut raw)?; }
+    }
+    write(db, &mut batch, &mut raw)?;
+
+    let mut checksum = [0u8; 4];
+    read_exact(&mut reader, &mut checksum)?;
+    let checksum = u32::from_le_bytes(checksum);
+    if checksum != hasher.finalize() { return Err(DataError::ChecksumMismatch); }
+    if reader.read(&mut [0u8; 1])? != 0 { return Err(invalid("portable export has data past its checksum")); }
+
+    Ok(PortableManifest { format_version, keys, checksum })
+}
+
+fn write(db: &dyn Db, batch: &mut Vec<BatchOp>, raw: &mut Vec<(Vec<u8>, Vec<u8>)>) -> Result<(), DataError> {
+    if !batch.is_empty() { db.write_batch(batch)?; }
+    if !raw.is_empty() { db.put_stored(raw)?; }
+    batch.clear();
+    raw.clear();
+    Ok(())
+}
+
+fn write_hashed(writer: &mut impl Write, hasher: &mut Hasher, bytes: &[u8]) -> io::Result<()> {
+    hasher.update(bytes);
+    writer.write_all(bytes)
+}
+
+fn write_field(writer: &mut impl Write, hasher: &mut Hasher, field: &[u8]) -> io::Result<()> {
+    let Ok(len) = u32::try_from(field.len())
+        else { return Err(io::Error::new(io::ErrorKind::InvalidInput, "portable export field over 4 GiB")) };
+    write_hashed(writer, hasher, &len.to_le_bytes())?;
+    write_hashed(writer, hasher, field)
+}
+
+fn read_hashed(reader: &mut impl Read, hasher: &mut Hasher, buf: &mut [u8]) -> Result<(), DataError> {
+    read_exact(reader, buf)?;
+    hasher.update(buf);
+    Ok(())
+}
+
+// A corrupt length mustn't allocate its whole size up front, so fields are read through take
+fn read_field(reader: &mut impl Read, hasher: &mut Hasher) -> Result<Vec<u8>, DataError> {
+    let mut len = [0u8; 4];
+    read_hashed(reader, hasher, &mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+
+    let mut field = vec![];
+    reader.by_ref().take(len as u64).read_to_end(&mut field)?;
+    if field.len() != len { return Err(invalid("portable export is truncated")); }
+    hasher.update(&field);
+    Ok(field)
+}
+
+fn read_exact(reader: &mut impl Read, buf: &mut [u8]) -> Result<(), DataError> {
+    match reader.read_exact(buf) {
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Err(invalid("portable export is truncated")),
+        Err(err) => Err(err.into()),
+        Ok(_) => Ok(())
+    }
+}
+
+fn invalid(msg: &str) -> DataError {
+    DataError::DeserializationError(msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryDb;
+
+    fn exported(entries: Vec<(Vec<u8>, Vec<u8>)>) -> (Vec<u8>, PortableManifest) {
+        let db = MemoryDb::default();
+        for (key, value) in &entries { db.save_data(key, value).unwrap(); }
+        let mut stream = vec![];
+        let manifest = export(&db, &mut stream).unwrap();
+        (stream, manifest)
+    }
+
+    fn sample() -> (Vec<u8>, PortableManifest) {
+        exported(vec![(vec![], b"empty key".to_vec()), (b"a".to_vec(), vec![]), (b"b".to_vec(), b"value".to_vec())])
+    }
+
+    #[test]
+    fn round_trips_entries_and_manifest() {
+        let (stream, manifest) = sample();
+        assert_eq!(manifest, PortableManifest { format_version: FORMAT_VERSION, keys: 3, checksum: manifest.checksum });
+        assert_eq!(&stream[..MAGIC.len()], MAGIC);
+        assert_eq!(stream[stream.len() - 4..], manifest.checksum.to_le_bytes());
+        assert_eq!(manifest.checksum, crc32fast::hash(&stream[..stream.len() - 4]));
+
+        let db = MemoryDb::default();
+        assert_eq!(import(&db, &stream[..]).unwrap(), manifest);
+        assert_eq!(db.get_data(&vec![]).unwrap(), b"empty key");
+        assert_eq!(db.get_data(&b"a".to_vec()).unwrap(), b"");
+        assert_eq!(db.get_data(&b"b".to_vec()).unwrap(), b"value");
+    }
+
+    #[test]
+    fn round_trips_counters_raw() {
+        let db = MemoryDb::default();
+        let counter = merge::counter_key("orders");
+        db.increment(&counter).unwrap();
+        db.increment(&counter).unwrap();
+        let mut stream = vec![];
+        export(&db, &mut stream).unwrap();
+
+        let imported = MemoryDb::default();
+        import(&imported, &stream[..]).unwrap();
+        assert_eq!(imported.increment(&counter).unwrap(), 3);
+    }
+
+    #[test]
+    fn rejects_checksum_mismatch() {
+        let (mut stream, _) = sample();
+        let last_value_byte = stream.len() - 5;
+        stream[last_value_byte] ^= 0xFF;
+        assert_eq!(import(&MemoryDb::default(), &stream[..]), Err(DataError::ChecksumMismatch));
+
+        let (mut stream, _) = sample();
+        let last = stream.len() - 1;
+        stream[last] ^= 0xFF;
+        assert_eq!(import(&MemoryDb::default(), &stream[..]), Err(DataError::ChecksumMismatch));
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        let (stream, _) = sample();
+        for len in 0..stream.len() {
+            assert_eq!(import(&MemoryDb::default(), &stream[..len]), Err(invalid("portable export is truncated")), "cut at {len}");
+        }
+    }
+
+    #[test]
+    fn rejects_bad_framing() {
+        let (stream, _) = sample();
+
+        let mut bad_magic = stream.clone();
+        bad_magic[0] = b'X';
+        assert_eq!(import(&MemoryDb::default(), &bad_magic[..]), Err(invalid("not a portable export")));
+
+        let mut bad_version = stream.clone();
+        bad_version[MAGIC.len()] = 2;
+        assert_eq!(import(&MemoryDb::default(), &bad_version[..]), Err(invalid("unsupported portable export version 2")));
+
+        let mut trailing = stream.clone();
+        trailing.push(0);
+        assert_eq!(import(&MemoryDb::default(), &trailing[..]), Err(invalid("portable export has data past its checksum")));
+    }
+}
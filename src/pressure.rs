@@ -0,0 +1,70 @@
+use std::sync::{Arc, Once, RwLock};
+use std::thread;
+use std::time::Duration;
+use moka::sync::Cache;
+use crate::{CacheKey, SafeDataProvider};
+
+type PressureSignal = Arc<dyn Fn() -> f64 + Send + Sync>;
+
+static SIGNAL: RwLock<Option<(PressureSignal, Duration)>> = RwLock::new(None);
+static MONITOR: Once = Once::new();
+
+pub(crate) fn set_signal(signal: PressureSignal, poll_every: Duration) {
+    let mut current = SIGNAL.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    *current = Some((signal, poll_every));
+    drop(current);
+    MONITOR.call_once(|| { thread::spawn(monitor); });
+}
+
+fn monitor() {
+    // Each cache's size when pressure last rose above 0. An unbounded cache is trimmed relative
+    // to it, so a steady pressure holds the cache at one target instead of cutting it by the
+    // same fraction again on every poll.
+    let mut references: Option<[u64; 4]> = None;
+    loop {
+        // cloned out so replacing the signal isn't held up by a trim pass
+        let (signal, poll_every) = {
+            let Ok(current) = SIGNAL.read()
+                else { return };
+            let Some((signal, poll_every)) = current.as_ref()
+                else { return };
+            (signal.clone(), *poll_every)
+        };
+
+        let pressure = signal().clamp(0.0, 1.0);
+        match pressure > 0.0 {
+            false => references = None,
+            true => {
+                let config = SafeDataProvider::get_config();
+                let capacity = config.cache_capacity.filter(|_| config.cache_memory_budget_mb.is_none());
+                let (tokens, lazy_tokens) = (SafeDataProvider::get_token_cache(), SafeDataProvider::get_lazy_token_cache());
+                let (data, shared_data) = (SafeDataProvider::get_data_cache(), SafeDataProvider::get_shared_data_cache());
+                let references = *references.get_or_insert_with(|| {
+                    [size(tokens), size(lazy_tokens), size(data), size(shared_data)]
+                });
+                trim(tokens, capacity.unwrap_or(references[0]), pressure);
+                trim(lazy_tokens, capacity.unwrap_or(references[1]), pressure);
+                trim(data, capacity.unwrap_or(references[2]), pressure);
+                trim(shared_data, capacity.unwrap_or(references[3]), pressure);
+            }
+        }
+        thread::sleep(poll_every);
+    }
+}
+
+fn size<V: Clone + Send + Sync + 'static>(cache: &Cache<CacheKey, V>) -> u64 {
+    cache.run_pending_tasks();
+    cache.entry_count()
+}
+
+// moka can't change a cache's capacity once built, so entries over the pressure-scaled limit
+// are invalidated here instead. They come out in moka's iteration order, not by recency.
+fn trim<V: Clone + Send + Sync + 'static>(cache: &Cache<CacheKey, V>, reference: u64, pressure: f64) {
+    let count = size(cache);
+    let limit = (reference as f64 * (1.0 - pressure)) as u64;
+    if count <= limit { return; }
+
+    for (cache_key, _) in cache.iter().take((count - limit) as usize) {
+        cache.invalidate(&*cache_key);
+    }
+}
@@ -0,0 +1,318 @@
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use pneumatic_core::tokens::Token;
+use crate::memory::MemoryDb;
+use crate::{merge, BatchOp, BlockCacheUsage, ChangeRecord, CompressionHint, DataError, Db};
+
+/// Which backend a routed partition keeps a key in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BackendId {
+    /// The partition's RocksDB instance.
+    RocksDb,
+    /// An in-memory store, lost when the process exits.
+    Memory
+}
+
+/// Picks the backend for a key. It must be deterministic: a key routed somewhere else than it
+/// was saved to reads as missing.
+pub type Router = Arc<dyn Fn(&[u8]) -> BackendId + Send + Sync>;
+
+type Scan<'a> = &'a dyn Fn(&dyn Db, Option<&[u8]>, Option<&[u8]>, &mut dyn FnMut(&[u8], &[u8]) -> bool)
+    -> Result<(), DataError>;
+
+/// A partition split over several backends by its router. Each key lives in exactly one of
+/// them, and scans merge the backends back into a single ordered keyspace. Writes touching
+/// both backends (batches, bulk loads) are only atomic per backend. Namespaced data and the
+/// provider's reserved keys always go to RocksDB. MemoryDb has no write checks of its own, so
+/// writes to it are checked against RocksDB's: refused while it's read-only, and kept from
+/// overwriting or deleting anything when the partition is append-only.
+pub(crate) struct RoutedDb {
+    durable: Arc<dyn Db>,
+    memory: Arc<MemoryDb>,
+    router: Router
+}
+
+impl RoutedDb {
+    pub(crate) fn new(durable: Arc<dyn Db>, memory: Arc<MemoryDb>, router: Router) -> Self {
+        RoutedDb { durable, memory, router }
+    }
+
+    // counters and refcounts are only useful if they survive a restart
+    fn route(&self, key: &[u8]) -> BackendId {
+        match merge::is_reserved(key) {
+            true => BackendId::RocksDb,
+            false => (self.router)(key)
+        }
+    }
+
+    fn backend(&self, key: &[u8]) -> &dyn Db {
+        match self.route(key) {
+            BackendId::RocksDb => self.durable.as_ref(),
+            BackendId::Memory => self.memory.as_ref()
+        }
+    }
+
+    // The backend for a save to `key`, once a save there is allowed
+    fn writer(&self, key: &Vec<u8>) -> Result<&dyn Db, DataError> {
+        match self.route(key) {
+            BackendId::RocksDb => Ok(self.durable.as_ref()),
+            BackendId::Memory => {
+                self.check_memory_put(key)?;
+                Ok(self.memory.as_ref())
+            }
+        }
+    }
+
+    // Like writer, for merges, which append-only partitions still take
+    fn merger(&self, key: &Vec<u8>) -> Result<&dyn Db, DataError> {
+        match self.route(key) {
+            BackendId::RocksDb => Ok(self.durable.as_ref()),
+            BackendId::Memory => {
+                self.durable.writable()?;
+                Ok(self.memory.as_ref())
+            }
+        }
+    }
+
+    fn check_memory_put(&self, key: &Vec<u8>) -> Result<(), DataError> {
+        self.durable.writable()?;
+        if !self.durable.append_only() { return Ok(()); }
+        match self.memory.get_stored(key) {
+            Err(DataError::DataNotFound) => Ok(()),
+            Err(err) => Err(err),
+            Ok(_) => Err(DataError::AlreadyExists)
+        }
+    }
+
+    fn check_memory_delete(&self) -> Result<(), DataError> {
+        self.durable.writable()?;
+        match self.durable.append_only() {
+            true => Err(DataError::OperationNotAllowed),
+            false => Ok(())
+        }
+    }
+
+    // Memory entries are collected up front, being cheap to copy, and slotted in between the
+    // durable entries as that scan goes by. Entries a backend holds for keys that now route
+    // elsewhere are skipped, so every key comes from where reads of it would go.
+    fn merged_scan(&self, start: Option<&[u8]>, end: Option<&[u8]>,
+                   visit: &mut dyn FnMut(&[u8], &[u8]) -> bool, scan: Scan) -> Result<(), DataError> {
+        let mut in_memory = vec![];
+        scan(self.memory.as_ref(), start, end, &mut |key, value| {
+            if self.route(key) == BackendId::Memory { in_memory.push((key.to_vec(), value.to_vec())); }
+            true
+        })?;
+
+        let mut in_memory = in_memory.into_iter().peekable();
+        let mut stopped = false;
+        scan(self.durable.as_ref(), start, end, &mut |key, value| {
+            if self.route(key) != BackendId::RocksDb { return true; }
+            while let Some((memory_key, memory_value)) = in_memory.next_if(|(memory_key, _)| memory_key.as_slice() < key) {
+                stopped = !visit(&memory_key, &memory_value);
+                if stopped { return false; }
+            }
+            stopped = !visit(key, value);
+            !stopped
+        })?;
+
+        if stopped { return Ok(()); }
+        for (key, value) in in_memory {
+            if !visit(&key, &value) { break; }
+        }
+        Ok(())
+    }
+}
+
+impl Db for RoutedDb {
+    fn get_token(&self, key: &Vec<u8>) -> Result<Token, DataError> {
+        self.backend(key).get_token(key)
+    }
+
+    fn save_token(&self, key: &Vec<u8>, token: &Arc<RwLock<Token>>) -> Result<(), DataError> {
+        self.writer(key)?.save_token(key, token)
+    }
+
+    fn get_data(&self, key: &Vec<u8>) -> Result<Vec<u8>, DataError> {
+        self.backend(key).get_data(key)
+    }
+
+    fn save_data(&self, key: &Vec<u8>, data: &[u8]) -> Result<(), DataError> {
+        self.writer(key)?.save_data(key, data)
+    }
+
+    fn get_stored(&self, key: &Vec<u8>) -> Result<Vec<u8>, DataError> {
+        self.backend(key).get_stored(key)
+    }
+
+    fn put_stored(&self, entries: &[(Vec<u8>, Vec<u8>)]) -> Result<(), DataError> {
+        let (durable, memory): (Vec<_>, Vec<_>) = entries.iter().cloned()
+            .partition(|(key, _)| self.route(key) == BackendId::RocksDb);
+        for (key, _) in &memory { self.check_memory_put(key)?; }
+        if !durable.is_empty() { self.durable.put_stored(&durable)?; }
+        self.memory.put_stored(&memory)
+    }
+
+    fn get_data_with_expiry(&self, key: &Vec<u8>) -> Result<(Vec<u8>, Option<SystemTime>), DataError> {
+        self.backend(key).get_data_with_expiry(key)
+    }
+
+    fn save_data_hinted(&self, key: &Vec<u8>, data: &[u8], hint: CompressionHint) -> Result<(), DataError> {
+        self.writer(key)?.save_data_hinted(key, data, hint)
+    }
+
+    fn save_data_tagged(&self, key: &Vec<u8>, data: &[u8], type_tag: &str) -> Result<(), DataError> {
+        self.writer(key)?.save_data_tagged(key, data, type_tag)
+    }
+
+    fn type_tag(&self, key: &Vec<u8>) -> Result<Option<String>, DataError> {
+        self.backend(key).type_tag(key)
+    }
+
+    fn save_data_until(&self, key: &Vec<u8>, data: &[u8], expires_at: SystemTime) -> Result<(), DataError> {
+        self.writer(key)?.save_data_until(key, data, expires_at)
+    }
+
+    fn merge(&self, key: &Vec<u8>, operand: &[u8]) -> Result<(), DataError> {
+        self.merger(key)?.merge(key, operand)
+    }
+
+    fn increment(&self, key: &Vec<u8>) -> Result<u64, DataError> {
+        self.merger(key)?.increment(key)
+    }
+
+    fn adjust_refcount(&self, key: &Vec<u8>, delta: i64) -> Result<i64, DataError> {
+        self.merger(key)?.adjust_refcount(key, delta)
+    }
+
+    fn flush(&self) -> Result<(), DataError> {
+        self.durable.flush()
+    }
+
+    fn sync_wal(&self) -> Result<(), DataError> {
+        self.durable.sync_wal()
+    }
+
+    fn scan(&self, start: Option<&[u8]>, end: Option<&[u8]>,
+            visit: &mut dyn FnMut(&[u8], &[u8]) -> bool) -> Result<(), DataError> {
+        self.merged_scan(start, end, visit, &|db, start, end, visit| db.scan(start, end, visit))
+    }
+
+    fn scan_stored(&self, start: Option<&[u8]>, end: Option<&[u8]>,
+                   visit: &mut dyn FnMut(&[u8], &[u8]) -> bool) -> Result<(), DataError> {
+        self.merged_scan(start, end, visit, &|db, start, end, visit| db.scan_stored(start, end, visit))
+    }
+
+    fn scan_keys(&self, start: Option<&[u8]>, end: Option<&[u8]>,
+                 visit: &mut dyn FnMut(&[u8]) -> bool) -> Result<(), DataError> {
+        self.merged_scan(start, end, &mut |key, _| visit(key), &|db, start, end, visit| {
+            db.scan_keys(start, end, &mut |key| visit(key, &[]))
+        })
+    }
+
+    fn write_batch(&self, ops: &[BatchOp]) -> Result<(), DataError> {
+        let (mut durable_ops, mut memory_ops) = (vec![], vec![]);
+        for op in ops {
+            let (key, op) = match op {
+                BatchOp::Put(key, value) => (key, BatchOp::Put(key.clone(), value.clone())),
+                BatchOp::Delete(key) => (key, BatchOp::Delete(key.clone()))
+            };
+            match self.route(key) {
+                BackendId::RocksDb => durable_ops.push(op),
+                BackendId::Memory => memory_ops.push(op)
+            }
+        }
+
+        // checked before either backend is written, so a refused memory write fails it whole
+        for op in &memory_ops {
+            match op {
+                BatchOp::Put(key, _) => self.check_memory_put(key)?,
+                BatchOp::Delete(_) => self.check_memory_delete()?
+            }
+        }
+
+        if !durable_ops.is_empty() { self.durable.write_batch(&durable_ops)?; }
+        self.memory.write_batch(&memory_ops)
+    }
+
+    fn ingest(&self, shards: &[&[(Vec<u8>, Vec<u8>)]]) -> Result<(), DataError> {
+        // splitting a sorted shard keeps both halves sorted and within the shard's range
+        let (mut durable_shards, mut memory_entries) = (vec![], vec![]);
+        for shard in shards {
+            let (durable, memory): (Vec<_>, Vec<_>) = shard.iter().cloned()
+                .partition(|(key, _)| self.route(key) == BackendId::RocksDb);
+            if !durable.is_empty() { durable_shards.push(durable); }
+            memory_entries.extend(memory);
+        }
+        for (key, _) in &memory_entries { self.check_memory_put(key)?; }
+
+        let durable_shards: Vec<&[(Vec<u8>, Vec<u8>)]> = durable_shards.iter().map(|shard| shard.as_slice()).collect();
+        if !durable_shards.is_empty() { self.durable.ingest(&durable_shards)?; }
+        self.memory.ingest(&[memory_entries.as_slice()])
+    }
+
+    fn write_amplification(&self) -> Result<f64, DataError> {
+        self.durable.write_amplification()
+    }
+
+    fn block_cache_usage(&self) -> Result<BlockCacheUsage, DataError> {
+        self.durable.block_cache_usage()
+    }
+
+    fn compact(&self) -> Result<(), DataError> {
+        self.durable.compact()
+    }
+
+    fn set_auto_compaction(&self, enabled: bool) -> Result<(), DataError> {
+        self.durable.set_auto_compaction(enabled)
+    }
+
+    fn checkpoint(&self, dest: &Path) -> Result<(), DataError> {
+        self.durable.checkpoint(dest)
+    }
+
+    fn disk_size(&self) -> Result<u64, DataError> {
+        self.durable.disk_size()
+    }
+
+    fn changes_since(&self, since: u64, visit: &mut dyn FnMut(ChangeRecord) -> bool) -> Result<(), DataError> {
+        self.durable.changes_since(since, visit)
+    }
+
+    fn catch_up(&self) -> Result<bool, DataError> {
+        self.durable.catch_up()
+    }
+
+    fn replica_lag(&self) -> Result<u64, DataError> {
+        self.durable.replica_lag()
+    }
+
+    fn verify(&self) -> Result<u64, DataError> {
+        Ok(self.durable.verify()? + self.memory.verify()?)
+    }
+
+    fn value_size(&self, key: &Vec<u8>) -> Result<Option<usize>, DataError> {
+        self.backend(key).value_size(key)
+    }
+
+    fn writable(&self) -> Result<(), DataError> {
+        self.durable.writable()
+    }
+
+    fn append_only(&self) -> bool {
+        self.durable.append_only()
+    }
+
+    fn get_in(&self, namespace: &str, key: &Vec<u8>) -> Result<Vec<u8>, DataError> {
+        self.durable.get_in(namespace, key)
+    }
+
+    fn save_in(&self, namespace: &str, key: &Vec<u8>, data: &[u8]) -> Result<(), DataError> {
+        self.durable.save_in(namespace, key, data)
+    }
+
+    fn scan_in(&self, namespace: &str, start: Option<&[u8]>, end: Option<&[u8]>,
+               visit: &mut dyn FnMut(&[u8], &[u8]) -> bool) -> Result<(), DataError> {
+        self.durable.scan_in(namespace, start, end, visit)
+    }
+}
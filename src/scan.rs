@@ -0,0 +1,278 @@
+use std::cmp::Ordering;
+use std::mem;
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::thread;
+use pneumatic_core::encoding::deserialize_rmp_to;
+use pneumatic_core::tokens::Token;
+use crate::{hooks, DataError};
+
+// entries cross the channel in chunks rather than one at a time
+const CHUNK_SIZE: usize = 256;
+const CHUNKS_IN_FLIGHT: usize = 4;
+
+/// A scan streamed from a consistent point-in-time view of a partition. The scan runs on its
+/// own thread, reading from a RocksDB iterator (and so from the implicit snapshot it pins when
+/// created); by the time the stream is handed out that iterator exists, so writes made after
+/// that are never seen. The snapshot is released once the stream is exhausted or dropped.
+pub struct ScanStream<T> {
+    receiver: Receiver<Result<Vec<T>, DataError>>,
+    chunk: std::vec::IntoIter<T>,
+    pending_error: Option<DataError>,
+    op: &'static str,
+    partition_id: String
+}
+
+impl<T: Send + 'static> ScanStream<T> {
+    /// Runs `produce` on a new thread, streaming everything it emits. `produce` must stop once
+    /// emit returns false, which happens when the stream has been dropped.
+    pub(crate) fn new<F>(op: &'static str, partition_id: &str, produce: F) -> Self
+        where F: FnOnce(&mut dyn FnMut(T) -> bool) -> Result<(), DataError> + Send + 'static {
+        let (sender, receiver) = sync_channel(CHUNKS_IN_FLIGHT);
+        thread::spawn(move || {
+            let mut chunk = vec![];
+            let mut sent_any = false;
+            let result = produce(&mut |item| {
+                chunk.push(item);
+                // the first entry goes out on its own, to tell the stream the scan is underway
+                if sent_any && chunk.len() < CHUNK_SIZE { return true; }
+                sent_any = true;
+                sender.send(Ok(mem::take(&mut chunk))).is_ok()
+            });
+
+            let _ = match result {
+                Err(err) => sender.send(Err(err)),
+                Ok(_) if !chunk.is_empty() => sender.send(Ok(chunk)),
+                Ok(_) => Ok(())
+            };
+        });
+
+        let mut stream = ScanStream {
+            receiver,
+            chunk: vec![].into_iter(),
+            pending_error: None,
+            op,
+            partition_id: partition_id.to_string()
+        };
+
+        // wait until the scan has started (or finished), so its view is as of now
+        match stream.receiver.recv() {
+            Err(_) => {},
+            Ok(Err(err)) => stream.pending_error = Some(err),
+            Ok(Ok(chunk)) => stream.chunk = chunk.into_iter()
+        }
+        stream
+    }
+}
+
+impl<T> Iterator for ScanStream<T> {
+    type Item = Result<T, DataError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.chunk.next() { return Some(Ok(item)); }
+            if let Some(err) = self.pending_error.take() {
+                hooks::report_error(&err, &self.partition_id, self.op);
+                return Some(Err(err));
+            }
+
+            match self.receiver.recv() {
+                Err(_) => return None,
+                Ok(Err(err)) => self.pending_error = Some(err),
+                Ok(Ok(chunk)) => self.chunk = chunk.into_iter()
+            }
+        }
+    }
+}
+
+/// What a token scan does with an entry that doesn't deserialize into a Token.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScanErrorPolicy {
+    /// Yields the error and ends the scan.
+    Strict,
+    /// Skips the entry, reporting the error to the error hook.
+    SkipAndLog,
+    /// Skips the entry, remembering its key for [TokenScan::failed_keys].
+    Collect
+}
+
+/// A token scan applying a [ScanErrorPolicy]. Store errors are always yielded, whatever the
+/// policy, since the scan ends with them anyway.
+pub struct TokenScan {
+    entries: ScanStream<(Vec<u8>, Vec<u8>)>,
+    policy: ScanErrorPolicy,
+    failed_keys: Vec<Vec<u8>>,
+    done: bool
+}
+
+impl TokenScan {
+    pub(crate) fn new(entries: ScanStream<(Vec<u8>, Vec<u8>)>, policy: ScanErrorPolicy) -> Self {
+        TokenScan { entries, policy, failed_keys: vec![], done: false }
+    }
+
+    /// Keys skipped so far under ScanErrorPolicy::Collect; complete once the scan is exhausted.
+    pub fn failed_keys(&self) -> &[Vec<u8>] {
+        &self.failed_keys
+    }
+}
+
+impl Iterator for TokenScan {
+    type Item = Result<(Vec<u8>, Token), DataError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done { return None; }
+        loop {
+            let (key, data) = match self.entries.next()? {
+                Err(err) => return Some(Err(err)),
+                Ok(entry) => entry
+            };
+
+            let err = match deserialize_rmp_to::<Token>(&data) {
+                Err(err) => DataError::DeserializationError(err.to_string()),
+                Ok(token) => return Some(Ok((key, token)))
+            };
+
+            match self.policy {
+                ScanErrorPolicy::Strict => {
+                    self.done = true;
+                    hooks::report_error(&err, &self.entries.partition_id, self.entries.op);
+                    return Some(Err(err));
+                },
+                ScanErrorPolicy::SkipAndLog => hooks::report_error(&err, &self.entries.partition_id, self.entries.op),
+                ScanErrorPolicy::Collect => self.failed_keys.push(key)
+            }
+        }
+    }
+}
+
+/// An entry of a [MergeIter]: a key present in both partitions with both values, or in just
+/// one of them with its value.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MergeEntry {
+    Both(Vec<u8>, Vec<u8>, Vec<u8>),
+    OnlyA(Vec<u8>, Vec<u8>),
+    OnlyB(Vec<u8>, Vec<u8>)
+}
+
+/// Walks two partitions' scans side by side in key order, pairing up keys they share. Only
+/// the next entry of each side is held at a time. An error from either side is yielded and
+/// ends the iteration.
+pub struct MergeIter {
+    a: ScanStream<(Vec<u8>, Vec<u8>)>,
+    b: ScanStream<(Vec<u8>, Vec<u8>)>,
+    next_a: Option<(Vec<u8>, Vec<u8>)>,
+    next_b: Option<(Vec<u8>, Vec<u8>)>,
+    done: bool
+}
+
+impl MergeIter {
+    pub(crate) fn new(a: ScanStream<(Vec<u8>, Vec<u8>)>, b: ScanStream<(Vec<u8>, Vec<u8>)>) -> Self {
+        MergeIter { a, b, next_a: None, next_b: None, done: false }
+    }
+
+    fn fill(stream: &mut ScanStream<(Vec<u8>, Vec<u8>)>, next: &mut Option<(Vec<u8>, Vec<u8>)>)
+            -> Result<(), DataError> {
+        if next.is_none() { *next = stream.next().transpose()?; }
+        Ok(())
+    }
+}
+
+impl Iterator for MergeIter {
+    type Item = Result<MergeEntry, DataError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done { return None; }
+        let filled = Self::fill(&mut self.a, &mut self.next_a)
+            .and_then(|_| Self::fill(&mut self.b, &mut self.next_b));
+        if let Err(err) = filled {
+            self.done = true;
+            return Some(Err(err));
+        }
+
+        // whichever side is behind gives up its entry; the other keeps its for the next call
+        let entry = match (self.next_a.take(), self.next_b.take()) {
+            (None, None) => return None,
+            (Some((key, value)), None) => MergeEntry::OnlyA(key, value),
+            (None, Some((key, value))) => MergeEntry::OnlyB(key, value),
+            (Some((key_a, value_a)), Some((key_b, value_b))) => match key_a.cmp(&key_b) {
+                Ordering::Equal => MergeEntry::Both(key_a, value_a, value_b),
+                Ordering::Less => {
+                    self.next_b = Some((key_b, value_b));
+                    MergeEntry::OnlyA(key_a, value_a)
+                },
+                Ordering::Greater => {
+                    self.next_a = Some((key_a, value_a));
+                    MergeEntry::OnlyB(key_b, value_b)
+                }
+            }
+        };
+        Some(Ok(entry))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Entry = (Vec<u8>, Vec<u8>);
+
+    fn stream(entries: Vec<Result<Entry, DataError>>) -> ScanStream<Entry> {
+        ScanStream::new("test", "test", move |emit| {
+            for entry in entries {
+                if !emit(entry?) { break; }
+            }
+            Ok(())
+        })
+    }
+
+    fn entries<K: AsRef<[u8]>>(keys: &[K], side: &[u8]) -> Vec<Result<Entry, DataError>> {
+        keys.iter().map(|key| Ok((key.as_ref().to_vec(), side.to_vec()))).collect()
+    }
+
+    fn merged(a: Vec<Result<Entry, DataError>>, b: Vec<Result<Entry, DataError>>) -> Vec<Result<MergeEntry, DataError>> {
+        MergeIter::new(stream(a), stream(b)).collect()
+    }
+
+    #[test]
+    fn yields_keys_in_order() {
+        let merged = merged(entries(&[b"a", b"c", b"d"], b"A"), entries(&[b"b", b"c", b"e"], b"B"));
+        assert_eq!(merged, vec![
+            Ok(MergeEntry::OnlyA(b"a".to_vec(), b"A".to_vec())),
+            Ok(MergeEntry::OnlyB(b"b".to_vec(), b"B".to_vec())),
+            Ok(MergeEntry::Both(b"c".to_vec(), b"A".to_vec(), b"B".to_vec())),
+            Ok(MergeEntry::OnlyA(b"d".to_vec(), b"A".to_vec())),
+            Ok(MergeEntry::OnlyB(b"e".to_vec(), b"B".to_vec()))
+        ]);
+    }
+
+    #[test]
+    fn pairs_every_shared_key() {
+        let keys: Vec<Vec<u8>> = (0..CHUNK_SIZE as u32 * 3).map(|i| i.to_be_bytes().to_vec()).collect();
+        let merged = merged(entries(&keys, b"A"), entries(&keys, b"B"));
+        assert_eq!(merged.len(), keys.len());
+        for (entry, key) in merged.into_iter().zip(keys) {
+            assert_eq!(entry, Ok(MergeEntry::Both(key, b"A".to_vec(), b"B".to_vec())));
+        }
+    }
+
+    #[test]
+    fn drains_the_longer_side() {
+        assert!(merged(vec![], vec![]).is_empty());
+        assert_eq!(merged(entries(&[b"a", b"b"], b"A"), vec![]), vec![
+            Ok(MergeEntry::OnlyA(b"a".to_vec(), b"A".to_vec())),
+            Ok(MergeEntry::OnlyA(b"b".to_vec(), b"A".to_vec()))
+        ]);
+        assert_eq!(merged(vec![], entries(&[b"a"], b"B")), vec![Ok(MergeEntry::OnlyB(b"a".to_vec(), b"B".to_vec()))]);
+    }
+
+    #[test]
+    fn ends_at_an_error() {
+        let mut a = entries(&[b"a"], b"A");
+        a.push(Err(DataError::Poisoned));
+        a.extend(entries(&[b"c"], b"A"));
+        let merged = merged(a, entries(&[b"b", b"d"], b"B"));
+        assert_eq!(merged, vec![
+            Ok(MergeEntry::OnlyA(b"a".to_vec(), b"A".to_vec())),
+            Err(DataError::Poisoned)
+        ]);
+    }
+}
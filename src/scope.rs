@@ -0,0 +1,87 @@
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::time::{Duration, Instant};
+use crate::DataError;
+
+thread_local! {
+    static BUDGET: RefCell<Option<Budget>> = const { RefCell::new(None) };
+}
+
+struct Budget {
+    limit: Duration,
+    spent: Duration,
+    // set while a provider call is being timed, so calls it makes itself aren't counted twice
+    in_call: bool
+}
+
+/// Caps the total time one logical request spends in the data layer. While the scope is
+/// alive, every provider call made on the creating thread adds its duration to the scope, and
+/// once the total reaches the budget further calls fail with DataError::BudgetExceeded without
+/// touching the store. A call already underway when the budget runs out still completes.
+/// Work the provider hands to other threads (e.g. a ScanStream's scan) isn't counted. Scopes
+/// nest: a new one replaces the current one until it's dropped.
+pub struct RequestScope {
+    previous: Option<Budget>,
+    // the budget lives in a thread local, so the scope has to stay on its thread
+    _not_send: PhantomData<*const ()>
+}
+
+impl RequestScope {
+    pub fn with_budget(budget: Duration) -> Self {
+        let current = Budget { limit: budget, spent: Duration::ZERO, in_call: false };
+        let previous = BUDGET.with(|scoped| scoped.replace(Some(current)));
+        RequestScope { previous, _not_send: PhantomData }
+    }
+
+    /// Time spent in provider calls so far.
+    pub fn spent(&self) -> Duration {
+        BUDGET.with(|scoped| scoped.borrow().as_ref().map_or(Duration::ZERO, |budget| budget.spent))
+    }
+
+    pub fn remaining(&self) -> Duration {
+        BUDGET.with(|scoped| {
+            scoped.borrow().as_ref().map_or(Duration::ZERO, |budget| budget.limit.saturating_sub(budget.spent))
+        })
+    }
+}
+
+impl Drop for RequestScope {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        BUDGET.with(|scoped| *scoped.borrow_mut() = previous);
+    }
+}
+
+/// Runs a provider call against the current thread's budget, if there is one.
+pub(crate) fn metered<T>(f: impl FnOnce() -> Result<T, DataError>) -> Result<T, DataError> {
+    let timed = BUDGET.with(|scoped| match scoped.borrow_mut().as_mut() {
+        None => Ok(false),
+        Some(budget) if budget.in_call => Ok(false),
+        Some(budget) if budget.spent >= budget.limit => Err(DataError::BudgetExceeded),
+        Some(budget) => {
+            budget.in_call = true;
+            Ok(true)
+        }
+    })?;
+    if !timed { return f(); }
+
+    let _call = TimedCall { started: Instant::now() };
+    f()
+}
+
+// Charges the call to the budget when dropped, so a panicking call is still accounted for.
+struct TimedCall {
+    started: Instant
+}
+
+impl Drop for TimedCall {
+    fn drop(&mut self) {
+        let elapsed = self.started.elapsed();
+        BUDGET.with(|scoped| {
+            if let Some(budget) = scoped.borrow_mut().as_mut() {
+                budget.in_call = false;
+                budget.spent += elapsed;
+            }
+        });
+    }
+}
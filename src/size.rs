@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+use crate::SafeDataProvider;
+
+/// Called with the partition id, its new SizeLevel and its size in bytes.
+pub type SizeCallback = Arc<dyn Fn(&str, SizeLevel, u64) + Send + Sync>;
+
+/// Sizes in bytes at which a watched partition moves to SizeLevel::Warning and Critical.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SizeThresholds {
+    pub warning: u64,
+    pub critical: u64
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SizeLevel {
+    Normal,
+    Warning,
+    Critical
+}
+
+impl SizeThresholds {
+    fn level(&self, size: u64) -> SizeLevel {
+        match size {
+            size if size >= self.critical => SizeLevel::Critical,
+            size if size >= self.warning => SizeLevel::Warning,
+            _ => SizeLevel::Normal
+        }
+    }
+}
+
+// the current watch of each partition; a watcher thread that finds itself replaced stops
+static WATCHES: OnceLock<Mutex<HashMap<String, u64>>> = OnceLock::new();
+static NEXT_WATCH: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) fn watch(partition_id: &str, thresholds: SizeThresholds, poll_every: Duration, callback: SizeCallback) {
+    let watch_id = NEXT_WATCH.fetch_add(1, Ordering::Relaxed);
+    watches().lock().unwrap_or_else(|poisoned| poisoned.into_inner()).insert(partition_id.to_string(), watch_id);
+
+    let partition_id = partition_id.to_string();
+    thread::spawn(move || {
+        let mut level = SizeLevel::Normal;
+        loop {
+            if current_watch(&partition_id) != Some(watch_id) { return; }
+            // a closed partition isn't growing, and polling it shouldn't reopen it
+            if let Some(size) = SafeDataProvider::open_partition_size(&partition_id) {
+                let new_level = thresholds.level(size);
+                if new_level != level {
+                    level = new_level;
+                    callback(&partition_id, level, size);
+                }
+            }
+            thread::sleep(poll_every);
+        }
+    });
+}
+
+pub(crate) fn unwatch(partition_id: &str) {
+    watches().lock().unwrap_or_else(|poisoned| poisoned.into_inner()).remove(partition_id);
+}
+
+fn current_watch(partition_id: &str) -> Option<u64> {
+    watches().lock().unwrap_or_else(|poisoned| poisoned.into_inner()).get(partition_id).copied()
+}
+
+fn watches() -> &'static Mutex<HashMap<String, u64>> {
+    WATCHES.get_or_init(|| Mutex::new(HashMap::new()))
+}
@@ -0,0 +1,87 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Point-in-time copy of the provider's counters, all counted since start-up or the last
+/// reset_stats.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    pub token_cache_hits: u64,
+    pub token_cache_misses: u64,
+    pub data_cache_hits: u64,
+    pub data_cache_misses: u64,
+    pub db_reads: u64,
+    pub db_writes: u64,
+    pub db_scans: u64,
+    /// Writes that skipped the cache because of cache_insert_rate_limit.
+    pub cache_inserts_throttled: u64,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Stat {
+    TokenCacheHit,
+    TokenCacheMiss,
+    DataCacheHit,
+    DataCacheMiss,
+    DbRead,
+    DbWrite,
+    DbScan,
+    CacheInsertThrottled,
+}
+
+struct Counters {
+    token_cache_hits: AtomicU64,
+    token_cache_misses: AtomicU64,
+    data_cache_hits: AtomicU64,
+    data_cache_misses: AtomicU64,
+    db_reads: AtomicU64,
+    db_writes: AtomicU64,
+    db_scans: AtomicU64,
+    cache_inserts_throttled: AtomicU64,
+}
+
+static COUNTERS: Counters = Counters {
+    token_cache_hits: AtomicU64::new(0),
+    token_cache_misses: AtomicU64::new(0),
+    data_cache_hits: AtomicU64::new(0),
+    data_cache_misses: AtomicU64::new(0),
+    db_reads: AtomicU64::new(0),
+    db_writes: AtomicU64::new(0),
+    db_scans: AtomicU64::new(0),
+    cache_inserts_throttled: AtomicU64::new(0),
+};
+
+pub(crate) fn record(stat: Stat) {
+    counter(stat).fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn snapshot() -> StatsSnapshot {
+    StatsSnapshot {
+        token_cache_hits: COUNTERS.token_cache_hits.load(Ordering::Relaxed),
+        token_cache_misses: COUNTERS.token_cache_misses.load(Ordering::Relaxed),
+        data_cache_hits: COUNTERS.data_cache_hits.load(Ordering::Relaxed),
+        data_cache_misses: COUNTERS.data_cache_misses.load(Ordering::Relaxed),
+        db_reads: COUNTERS.db_reads.load(Ordering::Relaxed),
+        db_writes: COUNTERS.db_writes.load(Ordering::Relaxed),
+        db_scans: COUNTERS.db_scans.load(Ordering::Relaxed),
+        cache_inserts_throttled: COUNTERS.cache_inserts_throttled.load(Ordering::Relaxed),
+    }
+}
+
+pub(crate) fn reset() {
+    for stat in [Stat::TokenCacheHit, Stat::TokenCacheMiss, Stat::DataCacheHit, Stat::DataCacheMiss,
+                 Stat::DbRead, Stat::DbWrite, Stat::DbScan, Stat::CacheInsertThrottled] {
+        counter(stat).store(0, Ordering::Relaxed);
+    }
+}
+
+fn counter(stat: Stat) -> &'static AtomicU64 {
+    match stat {
+        Stat::TokenCacheHit => &COUNTERS.token_cache_hits,
+        Stat::TokenCacheMiss => &COUNTERS.token_cache_misses,
+        Stat::DataCacheHit => &COUNTERS.data_cache_hits,
+        Stat::DataCacheMiss => &COUNTERS.data_cache_misses,
+        Stat::DbRead => &COUNTERS.db_reads,
+        Stat::DbWrite => &COUNTERS.db_writes,
+        Stat::DbScan => &COUNTERS.db_scans,
+        Stat::CacheInsertThrottled => &COUNTERS.cache_inserts_throttled,
+    }
+}
@@ -0,0 +1,54 @@
+use std::sync::{Arc, RwLock};
+use crate::{prefix_end, DataBatch, DataError, SafeDataProvider, ScanStream};
+
+/// A named logical table within a partition: every key is stored with the table's name and a 0
+/// byte in front, so tables can't collide with each other as long as names don't contain a 0
+/// byte themselves. Nothing changes at the store level, so table entries go through the
+/// provider caches like any other data, and a table needs no setup before use.
+pub struct Table {
+    partition_id: String,
+    prefix: Vec<u8>
+}
+
+impl Table {
+    pub(crate) fn new(name: &str, partition_id: &str) -> Self {
+        let mut prefix = name.as_bytes().to_vec();
+        prefix.push(0);
+        Table { partition_id: partition_id.to_string(), prefix }
+    }
+
+    pub fn get(&self, key: &Vec<u8>) -> Result<Arc<RwLock<Vec<u8>>>, DataError> {
+        SafeDataProvider::get_data(&self.table_key(key), &self.partition_id)
+    }
+
+    pub fn save(&self, key: &Vec<u8>, data: Vec<u8>) -> Result<(), DataError> {
+        SafeDataProvider::save_data(&self.table_key(key), data, &self.partition_id)
+    }
+
+    pub fn delete(&self, key: &Vec<u8>) -> Result<(), DataError> {
+        let mut batch = DataBatch::new(&self.partition_id);
+        batch.delete(self.table_key(key));
+        batch.commit()
+    }
+
+    /// Streams every entry of the table, in key order and with the table prefix stripped, from
+    /// a snapshot (see [ScanStream]).
+    pub fn scan(&self) -> Result<ScanStream<(Vec<u8>, Vec<u8>)>, DataError> {
+        SafeDataProvider::observe("table_scan", &self.partition_id, || {
+            let db = SafeDataProvider::get_db(&self.partition_id)?;
+            let prefix = self.prefix.clone();
+            let end = prefix_end(&prefix);
+            Ok(ScanStream::new("table_scan", &self.partition_id, move |emit| {
+                db.scan(Some(prefix.as_slice()), end.as_deref(), &mut |key, value| {
+                    emit((key[prefix.len()..].to_vec(), value.to_vec()))
+                })
+            }))
+        })
+    }
+
+    fn table_key(&self, key: &Vec<u8>) -> Vec<u8> {
+        let mut table_key = self.prefix.clone();
+        table_key.extend_from_slice(key);
+        table_key
+    }
+}
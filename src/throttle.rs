@@ -0,0 +1,23 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Counted in one-second windows: cheap to check on every write, and bursty enough at window
+// edges that it doesn't matter for keeping eviction work bounded.
+static WINDOW: Mutex<Option<(Instant, u64)>> = Mutex::new(None);
+
+/// Whether a write may insert into the cache, given at most `per_second` such inserts.
+pub(crate) fn admit(per_second: u64) -> bool {
+    let mut window = WINDOW.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let now = Instant::now();
+    match window.as_mut() {
+        Some((started, count)) if now.duration_since(*started) < Duration::from_secs(1) => {
+            if *count >= per_second { return false; }
+            *count += 1;
+            true
+        },
+        _ => {
+            *window = Some((now, 1));
+            per_second > 0
+        }
+    }
+}